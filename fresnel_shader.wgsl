@@ -0,0 +1,67 @@
+// Example of a view dependent effect, edges facing away from the camera glow brighter.
+// Use it as the SourceFile of a module with Triangles so the mesh normals are meaningful
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    // World space position of the camera, w is always 1
+    view_position: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+@group(0) @binding(2)
+var<uniform> audio_in: f32;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec3<f32>,
+    @location(2) index: f32,
+    @location(3) normal: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    // Tint from the module's instance color function, white when it has none
+    @location(9) color: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.color = model.color * instance.color;
+    out.world_position = world_position.xyz;
+    // Instances are only rotated and uniformly scaled, so the model matrix works for normals
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let to_camera = normalize(camera.view_position.xyz - in.world_position);
+    let normal = normalize(in.world_normal);
+    // 0 facing the camera, 1 at the silhouette
+    let fresnel = pow(1.0 - abs(dot(normal, to_camera)), 3.0);
+    let glow = fresnel * (1.0 + audio_in * 4.0);
+    return vec4<f32>(in.color * (0.2 + glow), 1.0);
+}
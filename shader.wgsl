@@ -1,7 +1,14 @@
 
+// Declarations shared between shaders can be kept in their own file and pulled in with a line like
+// #include "uniforms.wgsl"
+// which is replaced by that file's contents, relative to this file
+
 // Vertex shader
+// view_position is the camera's position in world space with w = 1, for effects that depend
+// on the direction to the eye. See fresnel_shader.wgsl for an example
 struct CameraUniform {
     view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
 };
 
 @group(0) @binding(0) // 1.
@@ -10,11 +17,57 @@ var<uniform> camera: CameraUniform;
 var<uniform> time: f32;
 @group(0) @binding(2)
 var<uniform> audio_in: f32;
+// Frequency bands packed into vec4s, lowest frequency first. Declare it with
+// ceil(FftBands / 4) elements to use it, e.g. for the default of 8 bands:
+// @group(0) @binding(4)
+// var<uniform> audio_bands: array<vec4<f32>, 2>;
+// Last value sent to OscAddress when built with the osc feature:
+// @group(0) @binding(5)
+// var<uniform> osc_in: f32;
+// MidiInputs values packed into vec4s like the audio bands, with the midi feature:
+// @group(0) @binding(6)
+// var<uniform> midi: array<vec4<f32>, 1>;
+// Jumps to 1 on every detected beat and falls back to 0 over a quarter of a second:
+// @group(0) @binding(7)
+// var<uniform> beat: f32;
+// Levels of the left and right channel, the same as audio_in for mono input:
+// @group(0) @binding(8)
+// var<uniform> audio_left: f32;
+// @group(0) @binding(9)
+// var<uniform> audio_right: f32;
+// Cursor position in 0..1 window coordinates from the top left. The cursor is only tracked
+// while the window takes mouse input, so it stays put while ClickThrough is on:
+// @group(0) @binding(10)
+// var<uniform> mouse: vec2<f32>;
+// audio_in jumping to each new peak and holding it for PeakHoldTime seconds before falling back
+// over PeakDecayTime, for a VU meter style peak marker:
+// @group(0) @binding(11)
+// var<uniform> audio_peak: f32;
+// Past FFT bands, x is how many frames ago from newest to SpectrogramHistory and y is the
+// band. The texture can't be filtered, so sample it with textureLoad or the nearest sampler:
+// @group(1) @binding(0)
+// var spectrogram: texture_2d<f32>;
+// @group(1) @binding(1)
+// var spectrogram_sampler: sampler;
+// FftBins magnitudes evenly spaced in frequency, lowest first. Index it with the instance
+// index for one bar per bin, e.g. fft_bins[instance_index % arrayLength(&fft_bins)]. Backends
+// that can't read storage buffers in vertex shaders, like WebGL, only bind it to fragment shaders:
+// @group(2) @binding(0)
+// var<storage, read> fft_bins: array<f32>;
+// CustomUniforms from the module's ShaderData are in group 3, since groups 1 and 2 are taken by
+// the spectrogram and FFT bins. They are the members of one struct in the order they're listed,
+// each declared as f32 or vecN<f32> to match its number of values:
+// struct CustomUniforms {
+//     spikiness: f32,
+// };
+// @group(3) @binding(0)
+// var<uniform> custom: CustomUniforms;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) color: vec3<f32>,
     @location(2) index: f32,
+    @location(3) normal: vec3<f32>,
 }
 
 struct InstanceInput {
@@ -22,6 +75,8 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    // Tint from the module's instance color function, white when it has none
+    @location(9) color: vec3<f32>,
 };
 
 struct VertexOutput {
@@ -42,14 +97,20 @@ fn vs_main(
         instance.model_matrix_3,
     );
     var out: VertexOutput;
-    out.color = model.color.xyz;
+    out.color = model.color.xyz * instance.color;
     out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position.xyz, 1.0); // 2.
     out.index = model.index;
     return out;
 }
 
 // Fragment shader
+// ALPHA_CUTOFF is replaced with the AlphaCutoff setting for modules with the Alpha BlendMode and
+// with 0 for the others, so hard edged cutouts don't leave a fringe of nearly transparent pixels
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(in.color, 0.0);
+    let color = vec4<f32>(in.color, 0.0);
+    if (color.a < ALPHA_CUTOFF) {
+        discard;
+    }
+    return color;
 }
\ No newline at end of file
@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rustfft::{Fft, FftPlanner};
+use rustfft::num_complex::Complex;
+use serde::Deserialize;
+use crate::{AtomicF32, AUDIO_IN, AUDIO_LEFT, AUDIO_RIGHT};
+
+// Where the audio comes from. Loopback captures whatever the system is playing
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    Input,
+    Loopback,
+}
+
+// Response curve applied to every sample that makes it through the noise gate.
+// The curve is applied to twice the sample so that Sqrt matches the original response.
+// Decibel is the exception, it maps the envelope's dBFS from the floor up to 0 dB onto 0..1
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum AudioCurve {
+    Linear,
+    Sqrt,
+    Log,
+    Pow(f32),
+    Decibel,
+}
+
+impl AudioCurve {
+    pub fn apply(&self, sample: f32, decibel_floor: f32) -> f32 {
+        match self {
+            AudioCurve::Linear => sample * 2.0,
+            AudioCurve::Sqrt => f32::sqrt(sample * 2.0),
+            AudioCurve::Log => f32::ln(1.0 + sample * 2.0),
+            AudioCurve::Pow(exponent) => (sample * 2.0).powf(*exponent),
+            AudioCurve::Decibel => {
+                let decibels = 20.0 * sample.abs().max(f32::MIN_POSITIVE).log10();
+                let floor = decibel_floor.min(-1.0);
+                ((decibels - floor) / -floor).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+// A device from the AudioInputs setting and how much its level counts toward the mix
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AudioInput {
+    pub device: String,
+    #[serde(default = "default_input_weight")]
+    pub weight: f32,
+}
+
+fn default_input_weight() -> f32 { 1.0 }
+
+// Level of every input stream, shared between their callbacks. AUDIO_IN is the weighted sum
+pub struct InputMix {
+    levels: Vec<AtomicF32>,
+    weights: Vec<f32>,
+}
+
+impl InputMix {
+    pub fn new(weights: Vec<f32>) -> Self {
+        Self {
+            levels: weights.iter().map(|_| AtomicF32::zero()).collect(),
+            weights,
+        }
+    }
+
+    // Stores the level of one input and returns the new mix
+    pub fn set_level(&self, index: usize, level: f32) -> f32 {
+        self.levels[index].store(level);
+        self.levels.iter().zip(self.weights.iter()).map(|(level, weight)| level.load() * weight).sum()
+    }
+}
+
+// Peak the gain stops growing at, so silence isn't turned up into noise
+const AGC_MIN_PEAK: f32 = 0.05;
+
+// Automatic gain control. Follows a slow moving peak of the level and divides the level by it,
+// so the loudest recent sound comes out near 1 no matter how loud the input is
+pub struct AutoGain {
+    peak: f32,
+    // Seconds the peak takes to rise to a louder level, and to fall to a quieter one
+    attack: f32,
+    release: f32,
+}
+
+impl AutoGain {
+    pub fn new(attack: f32, release: f32) -> Self {
+        Self {
+            peak: AGC_MIN_PEAK,
+            attack,
+            release,
+        }
+    }
+
+    // dt is the seconds of audio since the last call
+    pub fn apply(&mut self, level: f32, dt: f32) -> f32 {
+        let time = if level > self.peak { self.attack } else { self.release };
+        let rate = if time > 0.0 { 1.0 - f32::exp(-dt / time) } else { 1.0 };
+        self.peak = (self.peak + (level - self.peak) * rate).max(AGC_MIN_PEAK);
+        level / self.peak
+    }
+}
+
+// Level that jumps to every new maximum and holds it for a while before falling, for the marker
+// that lingers above the level on a VU meter
+pub struct PeakHold {
+    peak: f32,
+    // Seconds left before the peak starts falling
+    hold_left: f32,
+    // Seconds a new peak is held, and seconds it then takes to fall from 1 to 0
+    hold_time: f32,
+    decay_time: f32,
+}
+
+impl PeakHold {
+    pub fn new(hold_time: f32, decay_time: f32) -> Self {
+        Self {
+            peak: 0.0,
+            hold_left: 0.0,
+            hold_time,
+            decay_time,
+        }
+    }
+
+    pub fn set_times(&mut self, hold_time: f32, decay_time: f32) {
+        self.hold_time = hold_time;
+        self.decay_time = decay_time;
+    }
+
+    // dt is the seconds since the last call
+    pub fn apply(&mut self, level: f32, dt: f32) -> f32 {
+        if level >= self.peak {
+            self.peak = level;
+            self.hold_left = self.hold_time;
+        } else if self.hold_left > 0.0 {
+            self.hold_left -= dt;
+        } else {
+            let fall = if self.decay_time > 0.0 { dt / self.decay_time } else { 1.0 };
+            self.peak = (self.peak - fall).max(level);
+        }
+        self.peak
+    }
+}
+
+// Number of samples collected before each FFT is run
+const FFT_SIZE: usize = 1024;
+
+// Magnitudes of each frequency band from the most recent FFT, lowest frequency first
+pub static AUDIO_BANDS: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+
+// Magnitudes from the most recent FFT grouped into evenly spaced bins, lowest frequency first
+pub static AUDIO_BINS: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+
+// Number of raw samples kept around for drawing the waveform
+const MAX_RECENT_SAMPLES: usize = 4096;
+
+// The most recent input samples mixed down to mono, oldest first
+pub static RECENT_SAMPLES: Mutex<VecDeque<f32>> = Mutex::new(VecDeque::new());
+
+pub fn push_recent_samples(data: &[f32]) {
+    let mut recent_samples = RECENT_SAMPLES.lock().unwrap();
+    recent_samples.extend(data.iter().copied());
+    let excess = recent_samples.len().saturating_sub(MAX_RECENT_SAMPLES);
+    recent_samples.drain(..excess);
+}
+
+// The last `count` samples, oldest first. Padded with silence at the start until enough
+// samples have come in
+pub fn recent_samples(count: usize) -> Vec<f32> {
+    let recent_samples = RECENT_SAMPLES.lock().unwrap();
+    let available = recent_samples.len().min(count);
+    let mut samples = vec![0.0; count - available];
+    samples.extend(recent_samples.iter().skip(recent_samples.len() - available));
+    samples
+}
+
+// Set when a beat is detected, the renderer clears it when it starts the pulse
+pub static BEAT_DETECTED: AtomicBool = AtomicBool::new(false);
+
+// Number of FFT blocks the average energy is taken over, about a second at 44.1 kHz
+const BEAT_HISTORY: usize = 43;
+// Energy below this is treated as silence so noise can't trigger beats
+const BEAT_MIN_ENERGY: f32 = 0.0001;
+
+// Energy based onset detection. A block is a beat when its energy is `sensitivity` times
+// the average energy of the blocks before it
+struct BeatDetector {
+    energies: VecDeque<f32>,
+    sensitivity: f32,
+    // Only the first block of a loud section is a beat
+    in_beat: bool,
+}
+
+impl BeatDetector {
+    fn new(sensitivity: f32) -> Self {
+        Self {
+            energies: VecDeque::with_capacity(BEAT_HISTORY),
+            sensitivity,
+            in_beat: false,
+        }
+    }
+
+    fn push_energy(&mut self, energy: f32) -> bool {
+        let average = if self.energies.is_empty() {
+            f32::MAX
+        } else {
+            self.energies.iter().sum::<f32>() / self.energies.len() as f32
+        };
+        if self.energies.len() == BEAT_HISTORY {
+            self.energies.pop_front();
+        }
+        self.energies.push_back(energy);
+
+        let loud = energy > BEAT_MIN_ENERGY && energy > average * self.sensitivity;
+        let beat = loud && !self.in_beat;
+        self.in_beat = loud;
+        beat
+    }
+}
+
+// Collects samples from the audio callback and splits them into frequency bands
+pub struct BandAnalyzer {
+    fft: Arc<dyn Fft<f32>>,
+    samples: Vec<f32>,
+    band_ranges: Vec<(usize, usize)>,
+    bin_ranges: Vec<(usize, usize)>,
+    beat_detector: BeatDetector,
+}
+
+impl BandAnalyzer {
+    pub fn new(band_count: usize, bin_count: usize, beat_sensitivity: f32) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+        *AUDIO_BANDS.lock().unwrap() = vec![0.0; band_count];
+        *AUDIO_BINS.lock().unwrap() = vec![0.0; bin_count];
+
+        Self {
+            fft,
+            samples: Vec::with_capacity(FFT_SIZE),
+            band_ranges: band_ranges(band_count, FFT_SIZE / 2),
+            bin_ranges: bin_ranges(bin_count, FFT_SIZE / 2),
+            beat_detector: BeatDetector::new(beat_sensitivity),
+        }
+    }
+
+    pub fn push_sample(&mut self, sample: f32) {
+        self.samples.push(sample);
+        if self.samples.len() == FFT_SIZE {
+            self.analyze();
+            self.samples.clear();
+        }
+    }
+
+    fn analyze(&mut self) {
+        // Apply a Hann window so the edges of the sample block don't leak into every bin
+        let mut buffer: Vec<Complex<f32>> = self.samples.iter().enumerate().map(|(index, &sample)| {
+            let window = 0.5 - 0.5 * f32::cos(2.0 * std::f32::consts::PI * index as f32 / (FFT_SIZE - 1) as f32);
+            Complex::new(sample * window, 0.0)
+        }).collect();
+        self.fft.process(&mut buffer);
+
+        let average_magnitude = |&(start, end): &(usize, usize)| {
+            let sum: f32 = buffer[start..end].iter().map(|bin| bin.norm()).sum();
+            sum / (end - start) as f32 / FFT_SIZE as f32 * 2.0
+        };
+        let bands: Vec<f32> = self.band_ranges.iter().map(average_magnitude).collect();
+        let bins: Vec<f32> = self.bin_ranges.iter().map(average_magnitude).collect();
+
+        let energy: f32 = bands.iter().map(|band| band * band).sum();
+        if self.beat_detector.push_energy(energy) {
+            BEAT_DETECTED.store(true, Ordering::Relaxed);
+        }
+
+        *AUDIO_BANDS.lock().unwrap() = bands;
+        *AUDIO_BINS.lock().unwrap() = bins;
+    }
+}
+
+// Splits the FFT bins (skipping the DC bin) into evenly spaced groups. With more groups than
+// bins some groups repeat the same bin
+fn bin_ranges(group_count: usize, bin_count: usize) -> Vec<(usize, usize)> {
+    let usable_bins = bin_count - 1;
+    (0..group_count).map(|group| {
+        let start = 1 + group * usable_bins / group_count;
+        let end = 1 + (group + 1) * usable_bins / group_count;
+        let start = start.min(bin_count - 1);
+        (start, end.clamp(start + 1, bin_count))
+    }).collect()
+}
+
+// Splits the FFT bins (skipping the DC bin) into logarithmically spaced bands so that the
+// low frequencies get as much resolution as the high frequencies
+fn band_ranges(band_count: usize, bin_count: usize) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    for band in 0..band_count {
+        let start = (bin_count as f32).powf(band as f32 / band_count as f32) as usize;
+        let end = (bin_count as f32).powf((band + 1) as f32 / band_count as f32) as usize;
+
+        let start = start.clamp(1, bin_count - 1);
+        let end = end.clamp(start + 1, bin_count);
+        ranges.push((start, end));
+    }
+    ranges
+}
+
+// Synthetic audio for --simulate-audio, so shaders can be worked on without any sound
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum SimulatedAudio {
+    // A sine sweeping from low to high frequencies and back
+    Sweep,
+    // A low tone hit on every beat at 120 BPM
+    Pulse,
+    // White noise that slowly swells and fades
+    Noise,
+}
+
+const SIMULATED_SAMPLE_RATE: f32 = 44100.0;
+const SWEEP_PERIOD: f32 = 8.0;
+const SWEEP_MIN_FREQUENCY: f32 = 50.0;
+const SWEEP_MAX_FREQUENCY: f32 = 10000.0;
+const PULSE_INTERVAL: f32 = 0.5;
+const PULSE_FREQUENCY: f32 = 80.0;
+const NOISE_SWELL_PERIOD: f32 = 4.0 * std::f32::consts::PI;
+// Frames longer than this only generate this much audio, so a stall doesn't take seconds to catch up
+const MAX_SIMULATED_STEP: f32 = 0.1;
+
+// Stands in for the audio stream, generating samples for the time every frame took and feeding
+// them through the same analysis as real input. The noise is seeded so every run is the same
+pub struct AudioSimulator {
+    pattern: SimulatedAudio,
+    audio_curve: AudioCurve,
+    decibel_floor: f32,
+    band_analyzer: BandAnalyzer,
+    rng: StdRng,
+    // Samples generated so far. Counted rather than summing the time of each sample, which
+    // drifts once an f32 time gets large
+    sample_index: u64,
+    phase: f32,
+}
+
+impl AudioSimulator {
+    pub fn new(pattern: SimulatedAudio, settings: &crate::Settings) -> Self {
+        Self {
+            pattern,
+            audio_curve: settings.audio_curve,
+            decibel_floor: settings.audio_decibel_floor,
+            band_analyzer: BandAnalyzer::new(settings.fft_bands, settings.fft_bins, settings.beat_sensitivity),
+            rng: StdRng::seed_from_u64(0),
+            sample_index: 0,
+            phase: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, dt: Duration) {
+        let sample_count = (dt.as_secs_f32().min(MAX_SIMULATED_STEP) * SIMULATED_SAMPLE_RATE) as usize;
+        let samples: Vec<f32> = (0..sample_count).map(|_| self.next_sample()).collect();
+        if samples.is_empty() {
+            return;
+        }
+
+        for &sample in &samples {
+            self.band_analyzer.push_sample(sample);
+        }
+        push_recent_samples(&samples);
+
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let level = self.audio_curve.apply(peak, self.decibel_floor);
+        AUDIO_IN.store(level);
+        AUDIO_LEFT.store(level);
+        AUDIO_RIGHT.store(level);
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        self.sample_index += 1;
+        match self.pattern {
+            SimulatedAudio::Sweep => {
+                // Up and back down on a log scale, so every octave takes as long
+                let position = 1.0 - (self.time_in_period(SWEEP_PERIOD) / SWEEP_PERIOD * 2.0 - 1.0).abs();
+                let frequency = SWEEP_MIN_FREQUENCY * (SWEEP_MAX_FREQUENCY / SWEEP_MIN_FREQUENCY).powf(position);
+                self.advance_phase(frequency);
+                0.5 * self.phase.sin()
+            }
+            SimulatedAudio::Pulse => {
+                let since_beat = self.time_in_period(PULSE_INTERVAL);
+                self.advance_phase(PULSE_FREQUENCY);
+                f32::exp(-since_beat * 12.0) * self.phase.sin()
+            }
+            SimulatedAudio::Noise => {
+                let swell = 0.5 - 0.5 * f32::cos(self.time_in_period(NOISE_SWELL_PERIOD) / NOISE_SWELL_PERIOD * 2.0 * std::f32::consts::PI);
+                swell * self.rng.gen_range(-0.5..0.5)
+            }
+        }
+    }
+
+    // Seconds since the start of the current period of the pattern, worked out from the sample
+    // count in f64 so it stays as precise however long the simulation runs
+    fn time_in_period(&self, period: f32) -> f32 {
+        (self.sample_index as f64 / SIMULATED_SAMPLE_RATE as f64 % period as f64) as f32
+    }
+
+    fn advance_phase(&mut self, frequency: f32) {
+        self.phase = (self.phase + 2.0 * std::f32::consts::PI * frequency / SIMULATED_SAMPLE_RATE)
+            % (2.0 * std::f32::consts::PI);
+    }
+}
@@ -1,3 +1,4 @@
+use serde::Deserialize;
 use wgpu::BindGroupLayout;
 
 use wgpu::util::DeviceExt;
@@ -11,7 +12,9 @@ use winit::dpi::PhysicalPosition;
 use winit::platform::windows::{WindowBuilderExtWindows};
 use winit::window::{Icon};
 
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering;
 
 mod avatar;
 mod model;
@@ -19,21 +22,128 @@ mod camera;
 mod renderer;
 mod texture;
 mod avatar_generator;
+mod screenshot;
+mod background;
+mod spectrogram;
+mod fft_bins;
+mod recorder;
+mod grid;
+mod shader_include;
+mod custom_uniforms;
 
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
+use cgmath::{Deg, Rad};
 use crate::graphics::camera::{Camera, CameraController, CameraUniform, Projection};
+pub use crate::graphics::camera::CameraRotationPath;
 use crate::graphics::renderer::Renderer;
-use crate::{AUDIO_IN, graphics, Settings};
+use crate::graphics::avatar_generator::AvatarPresets;
+pub use crate::graphics::avatar_generator::DEFAULT_AVATAR_DATA_PATH;
+use crate::{AUDIO_IN, AUDIO_LEFT, AUDIO_RELEASE, AUDIO_RIGHT, OSC_IN, graphics, Opt, Settings, SettingsWatcher};
+use crate::audio::{AUDIO_BANDS, AUDIO_BINS, AudioSimulator, BEAT_DETECTED, PeakHold};
 
 
-const BACKGROUND_COLOR: [f64; 4] = [0.0,0.0,0.0,0.0];
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum PresentMode {
+    Fifo, Mailbox, Immediate,
+}
+
+impl PresentMode {
+    fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Fifo => wgpu::PresentMode::Fifo,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
+
+// How the window's alpha is combined with what's behind it. Auto picks from what the surface
+// supports, preferring premultiplied alpha for transparent backgrounds so edges don't fringe
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum CompositeAlphaMode {
+    Auto, Opaque, PreMultiplied, PostMultiplied, Inherit,
+}
+
+impl CompositeAlphaMode {
+    fn choose(self, supported: &[wgpu::CompositeAlphaMode], transparent: bool) -> wgpu::CompositeAlphaMode {
+        let preferred: &[wgpu::CompositeAlphaMode] = match self {
+            CompositeAlphaMode::Auto if transparent => &[
+                wgpu::CompositeAlphaMode::PreMultiplied,
+                wgpu::CompositeAlphaMode::PostMultiplied,
+                wgpu::CompositeAlphaMode::Inherit,
+            ],
+            CompositeAlphaMode::Auto => &[wgpu::CompositeAlphaMode::Opaque],
+            CompositeAlphaMode::Opaque => &[wgpu::CompositeAlphaMode::Opaque],
+            CompositeAlphaMode::PreMultiplied => &[wgpu::CompositeAlphaMode::PreMultiplied],
+            CompositeAlphaMode::PostMultiplied => &[wgpu::CompositeAlphaMode::PostMultiplied],
+            CompositeAlphaMode::Inherit => &[wgpu::CompositeAlphaMode::Inherit],
+        };
+        match preferred.iter().find(|mode| supported.contains(mode)) {
+            Some(mode) => *mode,
+            None => {
+                if !matches!(self, CompositeAlphaMode::Auto) {
+                    log::warn!("Composite alpha mode {:?} is not supported, letting wgpu pick one", self);
+                }
+                wgpu::CompositeAlphaMode::Auto
+            }
+        }
+    }
+}
+
+// Graphics API to render with. Auto lets wgpu pick from every backend the platform has
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum Backend {
+    Auto, Vulkan, Dx12, Metal, Gl,
+}
+
+impl Backend {
+    fn supported(self) -> bool {
+        match self {
+            Backend::Auto => true,
+            Backend::Vulkan => cfg!(not(any(target_os = "macos", target_os = "ios", target_arch = "wasm32"))),
+            Backend::Dx12 => cfg!(windows),
+            Backend::Metal => cfg!(any(target_os = "macos", target_os = "ios")),
+            Backend::Gl => cfg!(not(any(target_os = "macos", target_os = "ios"))),
+        }
+    }
+
+    // Falls back to every backend when the chosen one isn't available on this platform
+    fn to_wgpu(self) -> wgpu::Backends {
+        if !self.supported() {
+            log::warn!("Backend {:?} is not supported on this platform, using any backend", self);
+            return wgpu::Backends::all();
+        }
+        match self {
+            Backend::Auto => wgpu::Backends::all(),
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::Gl => wgpu::Backends::GL,
+        }
+    }
+}
+
+// Seconds the beat uniform takes to fall from 1 to 0
+const BEAT_DECAY_TIME: f32 = 0.25;
+
+// Uniform buffers in the default bind group, one for each of bindings 0 to 11. Counted against
+// the device's limit of uniform buffers per shader stage along with a module's custom uniforms
+pub(crate) const DEFAULT_UNIFORM_BUFFER_COUNT: u32 = 12;
 
 struct DefaultBindGroups {
     camera_buffer: wgpu::Buffer,
     time_buffer: wgpu::Buffer,
     audio_buffer: wgpu::Buffer,
     keyboard_speed_buffer: wgpu::Buffer,
+    audio_bands_buffer: wgpu::Buffer,
+    osc_buffer: wgpu::Buffer,
+    midi_buffer: wgpu::Buffer,
+    beat_buffer: wgpu::Buffer,
+    audio_left_buffer: wgpu::Buffer,
+    audio_right_buffer: wgpu::Buffer,
+    mouse_buffer: wgpu::Buffer,
+    audio_peak_buffer: wgpu::Buffer,
 
     default_bind_group_layout: BindGroupLayout,
     default_bindings: wgpu::BindGroup,
@@ -41,7 +151,8 @@ struct DefaultBindGroups {
 
 #[rustfmt::skip]
 pub struct State {
-    surface: wgpu::Surface,
+    // None when rendering without a window, the config then only describes the offscreen target
+    surface: Option<wgpu::Surface>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
@@ -55,65 +166,140 @@ pub struct State {
 
     // time
     time: f32,
+    time_scale: f32,
+    time_loop_period: Option<f32>,
+    paused: bool,
+    // Jumps to 1 on every beat and falls back to 0 over BEAT_DECAY_TIME
+    beat: f32,
+    // AUDIO_IN with its peaks held, from the PeakHoldTime and PeakDecayTime settings
+    peak_hold: PeakHold,
+    // Real time the last update covered, recordings use it to time their frames
+    dt: Duration,
+
+    // FFT bands and bins blended over the frames, see smooth_fft
+    fft_smoothing: f32,
+    smoothed_bands: Vec<f32>,
+    smoothed_bins: Vec<f32>,
+
+    // Shortest time between two frames, from the MaxFps setting
+    min_frame_time: Option<Duration>,
+
+    background_color: wgpu::Color,
+    transparent_background: bool,
+    pub(crate) alpha_cutoff: f32,
+    screenshot_dir: PathBuf,
+
+    // Number of f32s in the audio bands uniform, padded to a multiple of 4
+    audio_bands_len: usize,
+    #[cfg(feature = "midi")]
+    midi_values_len: usize,
 
     default_bind_group: DefaultBindGroups,
+    // Bound to group 1 of every avatar module
+    spectrogram: spectrogram::Spectrogram,
+    // Bound to group 2 of every avatar module
+    fft_bins: fft_bins::FftBins,
     depth_texture: texture::Texture,
 
+    // MSAA sample count, and the color target to render into when it's above 1
+    sample_count: u32,
+    multisampled_framebuffer: Option<wgpu::TextureView>,
+
     mouse_pressed: bool,
+    // Cursor position in 0..1 window coordinates, from the top left
+    mouse: [f32; 2],
+    modifiers: ModifiersState,
+    // Whether the window takes mouse input. Toggling it also shows or hides the decorations
+    interactive: bool,
+    always_on_top: bool,
 }
 
 impl State {
-    async fn new(window: &Window, settings: &Settings) -> Self {
+    async fn new(window: &Window, settings: &Settings) -> anyhow::Result<Self> {
 
         let size = window.inner_size();
 
 
         // The instance is a handle to our GPU
-        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let backends = settings.backend.to_wgpu();
+        let instance = wgpu::Instance::new(backends);
         let surface = unsafe { instance.create_surface(window) };
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::empty(),
-                    // WebGL doesn't support all of wgpu's features, so if
-                    // we're building for the web we'll have to disable some.
-                    limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
+        let adapter = request_adapter(&instance, backends, Some(&surface)).await?;
 
+        let (device, queue) = request_device(&adapter).await?;
+
+        // Fifo is always supported so fall back to it when the requested mode isn't
+        let present_mode = settings.present_mode.to_wgpu();
+        let present_mode = if surface.get_supported_present_modes(&adapter).contains(&present_mode) {
+            present_mode
+        } else {
+            log::warn!("Present mode {:?} is not supported, falling back to Fifo", present_mode);
+            wgpu::PresentMode::Fifo
+        };
+
+        // Not every backend can copy out of the surface, the GL backend only allows rendering to it,
+        // so screenshots and recordings draw the frame again into a texture they can copy from
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface.get_supported_formats(&adapter)[0],
             width: size.width,
             height: size.height,
+            present_mode,
+            alpha_mode: settings.composite_alpha_mode.choose(
+                &surface.get_supported_alpha_modes(&adapter), settings.transparent_background),
+        };
+        surface.configure(&device, &config);
+
+        Ok(Self::with_device(Some(surface), &adapter, device, queue, config, settings))
+    }
+
+    // State without a window that renders into offscreen textures of the given size
+    async fn new_headless(width: u32, height: u32, settings: &Settings) -> anyhow::Result<Self> {
+        let backends = settings.backend.to_wgpu();
+        let instance = wgpu::Instance::new(backends);
+        let adapter = request_adapter(&instance, backends, None).await?;
+        let (device, queue) = request_device(&adapter).await?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: width.max(1),
+            height: height.max(1),
             present_mode: wgpu::PresentMode::Fifo,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
         };
-        surface.configure(&device, &config);
 
-        let depth_texture = texture::Texture::create_depth_texture(&device, &config, "depth_texture");
+        Ok(Self::with_device(None, &adapter, device, queue, config, settings))
+    }
+
+    fn with_device(
+        surface: Option<wgpu::Surface>,
+        adapter: &wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        config: wgpu::SurfaceConfiguration,
+        settings: &Settings,
+    ) -> Self {
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+        let background_color = background_color(settings, config.alpha_mode);
+
+        let sample_count = supported_sample_count(adapter, config.format, settings.msaa_samples);
+        let depth_texture = texture::Texture::create_depth_texture(&device, &config, sample_count, "depth_texture");
+        let spectrogram = spectrogram::Spectrogram::new(&device, settings.fft_bands, settings.spectrogram_history);
+        let fft_bins = fft_bins::FftBins::new(&device, settings.fft_bins, fft_bins_visibility(adapter));
+        let multisampled_framebuffer = if sample_count > 1 {
+            Some(texture::Texture::create_multisampled_framebuffer(&device, &config, sample_count))
+        } else {
+            None
+        };
 
         let camera = camera::Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
-        let projection = camera::Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0);
-        let camera_controller = camera::CameraController::new(4.0, 0.4, settings.camera_rotation);
+        let projection = camera::Projection::new(config.width, config.height, cgmath::Deg(settings.camera_fov), settings.camera_znear, settings.camera_zfar);
+        let mut camera_controller = camera::CameraController::new(settings.camera_speed, settings.camera_sensitivity, settings.camera_rotation);
+        camera_controller.set_home(settings.camera_home);
+        camera_controller.set_look_target(settings.look_target);
+        camera_controller.set_radius_limits(settings.camera_min_radius, settings.camera_max_radius);
+        camera_controller.set_rotation(settings.camera_rotation_speed, settings.camera_rotation_path);
 
         let mut camera_uniform = CameraUniform::new();
         camera_uniform.update_view_proj(&camera, &projection);
@@ -148,13 +334,67 @@ impl State {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        // Audio bands uniform. Uniform arrays need a 16 byte stride so the bands are packed
+        // into vec4s, shaders should declare it as array<vec4<f32>, ceil(FftBands / 4)>
+        let audio_bands_len = settings.fft_bands.max(1).next_multiple_of(4);
+        let audio_bands_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Audio Bands Buffer"),
+            contents: bytemuck::cast_slice(&vec![0.0f32; audio_bands_len]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Value received over OSC
+        let osc_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("OSC Buffer"),
+            contents: &[0,0,0,0],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // MIDI uniform, packed into vec4s the same way as the audio bands
+        let midi_values_len = midi_input_count(settings).max(1).next_multiple_of(4);
+        let midi_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("MIDI Buffer"),
+            contents: bytemuck::cast_slice(&vec![0.0f32; midi_values_len]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Beat pulse
+        let beat_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Beat Buffer"),
+            contents: &[0,0,0,0],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Stereo levels
+        let audio_left_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Audio Left Buffer"),
+            contents: &[0,0,0,0],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let audio_right_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Audio Right Buffer"),
+            contents: &[0,0,0,0],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let mouse_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Mouse Buffer"),
+            contents: bytemuck::cast_slice(&[0.5f32, 0.5]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let audio_peak_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Audio Peak Buffer"),
+            contents: &[0,0,0,0],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
         // Creating the bind group layout
         let default_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries:
                 &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    // The fragment stage can use the camera position for view dependent effects
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -191,6 +431,86 @@ impl State {
                         min_binding_size: None,
                     },
                     count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },],
                 label: Some("camera_bind_group_layout"),
             });
@@ -209,6 +529,30 @@ impl State {
             }, wgpu::BindGroupEntry {
                 binding: 3,
                 resource: keyboard_speed_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 4,
+                resource: audio_bands_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 5,
+                resource: osc_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 6,
+                resource: midi_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 7,
+                resource: beat_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 8,
+                resource: audio_left_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 9,
+                resource: audio_right_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 10,
+                resource: mouse_buffer.as_entire_binding(),
+            }, wgpu::BindGroupEntry {
+                binding: 11,
+                resource: audio_peak_buffer.as_entire_binding(),
             },],
             label: Some("default_bind_group"),
         });
@@ -219,6 +563,14 @@ impl State {
             time_buffer,
             audio_buffer,
             keyboard_speed_buffer,
+            audio_bands_buffer,
+            osc_buffer,
+            midi_buffer,
+            beat_buffer,
+            audio_left_buffer,
+            audio_right_buffer,
+            mouse_buffer,
+            audio_peak_buffer,
             default_bind_group_layout,
         };
 
@@ -236,11 +588,36 @@ impl State {
             camera_uniform,
 
             time: 0.0,
+            time_scale: settings.time_scale,
+            time_loop_period: settings.time_loop_period,
+            paused: false,
+            beat: 0.0,
+            peak_hold: PeakHold::new(settings.peak_hold_time, settings.peak_decay_time),
+            dt: Duration::ZERO,
+            fft_smoothing: settings.fft_smoothing,
+            smoothed_bands: Vec::new(),
+            smoothed_bins: Vec::new(),
+            min_frame_time: min_frame_time(settings),
+            background_color,
+            transparent_background: settings.transparent_background,
+            alpha_cutoff: settings.alpha_cutoff.clamp(0.0, 1.0),
+            screenshot_dir: PathBuf::from(&settings.screenshot_dir),
+            audio_bands_len,
+            #[cfg(feature = "midi")]
+            midi_values_len,
             default_bind_group: default_bind_group_struct,
+            spectrogram,
+            fft_bins,
 
             depth_texture,
+            sample_count,
+            multisampled_framebuffer,
 
             mouse_pressed: false,
+            mouse: [0.5, 0.5],
+            modifiers: ModifiersState::empty(),
+            interactive: !settings.click_through,
+            always_on_top: settings.always_on_top,
         }
     }
 
@@ -252,12 +629,38 @@ impl State {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.config);
+            }
             self.depth_texture =
-                texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+                texture::Texture::create_depth_texture(&self.device, &self.config, self.sample_count, "depth_texture");
+            if self.sample_count > 1 {
+                self.multisampled_framebuffer = Some(texture::Texture::create_multisampled_framebuffer(
+                    &self.device, &self.config, self.sample_count));
+            }
         }
     }
 
+    // Applies the settings that can be changed without recreating the window or surface
+    fn apply_settings(&mut self, settings: &Settings) {
+        self.camera_controller.set_speed(settings.camera_speed);
+        self.camera_controller.set_sensitivity(settings.camera_sensitivity);
+        self.camera_controller.set_home(settings.camera_home);
+        self.camera_controller.set_look_target(settings.look_target);
+        self.camera_controller.set_radius_limits(settings.camera_min_radius, settings.camera_max_radius);
+        self.camera_controller.set_rotation(settings.camera_rotation_speed, settings.camera_rotation_path);
+        self.projection.set_fov(Deg(settings.camera_fov));
+        self.projection.set_clip_planes(settings.camera_znear, settings.camera_zfar);
+        AUDIO_RELEASE.store(settings.audio_release());
+        self.background_color = background_color(settings, self.config.alpha_mode);
+        self.time_scale = settings.time_scale;
+        self.time_loop_period = settings.time_loop_period;
+        self.fft_smoothing = settings.fft_smoothing;
+        self.peak_hold.set_times(settings.peak_hold_time, settings.peak_decay_time);
+        self.min_frame_time = min_frame_time(settings);
+        self.alpha_cutoff = settings.alpha_cutoff.clamp(0.0, 1.0);
+    }
+
     fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
             WindowEvent::KeyboardInput {
@@ -269,8 +672,18 @@ impl State {
                 },
                 ..
             } => self.camera_controller.process_keyboard(*key, *state),
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                false
+            }
             WindowEvent::MouseWheel { delta, .. } => {
-                self.camera_controller.process_scroll(delta);
+                // Ctrl + scroll zooms by changing the field of view, plain scroll changes the distance
+                if self.modifiers.ctrl() {
+                    let fovy = self.projection.fovy() - Rad::from(Deg(camera::scroll_lines(delta) * 2.0));
+                    self.projection.set_fov(fovy);
+                } else {
+                    self.camera_controller.process_scroll(delta);
+                }
                 true
             }
             WindowEvent::MouseInput {
@@ -281,13 +694,29 @@ impl State {
                 self.mouse_pressed = *state == ElementState::Pressed;
                 true
             }
+            // Only arrives while the window takes mouse input, a click through window never sees
+            // the cursor so the uniform keeps its last position
+            WindowEvent::CursorMoved { position, .. } => {
+                self.mouse = [
+                    (position.x as f32 / self.size.width.max(1) as f32).clamp(0.0, 1.0),
+                    (position.y as f32 / self.size.height.max(1) as f32).clamp(0.0, 1.0),
+                ];
+                false
+            }
             _ => false,
         }
     }
 
-    unsafe fn update(&mut self, dt: std::time::Duration) {
-        // Update time
-        self.time += 0.05;
+    fn update(&mut self, dt: std::time::Duration) {
+        self.dt = dt;
+
+        // Update time, scaled by real time so animations run at the same speed at any frame rate
+        if !self.paused {
+            self.time += dt.as_secs_f32() * self.time_scale;
+            if let Some(period) = self.time_loop_period.filter(|period| *period > 0.0) {
+                self.time = self.time.rem_euclid(period);
+            }
+        }
 
         self.camera_controller.update_camera(&mut self.camera, dt);
         self.camera_uniform.update_view_proj(&self.camera, &self.projection);
@@ -304,36 +733,325 @@ impl State {
         self.queue.write_buffer(
             &self.default_bind_group.audio_buffer,
             0,
-            &AUDIO_IN.to_ne_bytes(),
+            &AUDIO_IN.load().to_ne_bytes(),
+        );
+        let audio_peak = self.peak_hold.apply(AUDIO_IN.load(), dt.as_secs_f32());
+        self.queue.write_buffer(
+            &self.default_bind_group.audio_peak_buffer,
+            0,
+            &audio_peak.to_ne_bytes(),
+        );
+        self.queue.write_buffer(
+            &self.default_bind_group.audio_left_buffer,
+            0,
+            &AUDIO_LEFT.load().to_ne_bytes(),
+        );
+        self.queue.write_buffer(
+            &self.default_bind_group.audio_right_buffer,
+            0,
+            &AUDIO_RIGHT.load().to_ne_bytes(),
+        );
+        self.beat = if BEAT_DETECTED.swap(false, Ordering::Relaxed) {
+            1.0
+        } else {
+            (self.beat - dt.as_secs_f32() / BEAT_DECAY_TIME).max(0.0)
+        };
+        self.queue.write_buffer(
+            &self.default_bind_group.beat_buffer,
+            0,
+            &self.beat.to_ne_bytes(),
+        );
+        self.queue.write_buffer(
+            &self.default_bind_group.mouse_buffer,
+            0,
+            bytemuck::cast_slice(&self.mouse),
+        );
+        self.queue.write_buffer(
+            &self.default_bind_group.osc_buffer,
+            0,
+            &OSC_IN.load().to_ne_bytes(),
+        );
+
+        smooth_fft(&mut self.smoothed_bands, &AUDIO_BANDS.lock().unwrap(), self.fft_smoothing, dt);
+        smooth_fft(&mut self.smoothed_bins, &AUDIO_BINS.lock().unwrap(), self.fft_smoothing, dt);
+        let mut audio_bands = self.smoothed_bands.clone();
+        self.spectrogram.push(&self.queue, &audio_bands);
+        audio_bands.resize(self.audio_bands_len, 0.0);
+        self.queue.write_buffer(
+            &self.default_bind_group.audio_bands_buffer,
+            0,
+            bytemuck::cast_slice(&audio_bands),
         );
+        self.fft_bins.write(&self.queue, &self.smoothed_bins);
+
+        #[cfg(feature = "midi")]
+        {
+            let mut midi_values = crate::midi::MIDI_VALUES.lock().unwrap().clone();
+            midi_values.resize(self.midi_values_len, 0.0);
+            self.queue.write_buffer(
+                &self.default_bind_group.midi_buffer,
+                0,
+                bytemuck::cast_slice(&midi_values),
+            );
+        }
+    }
+}
+
+// Looks for an adapter on the backends, and retries with the software fallback adapter before
+// giving up so machines without a usable GPU, like some VMs, can still run
+async fn request_adapter(
+    instance: &wgpu::Instance,
+    backends: wgpu::Backends,
+    compatible_surface: Option<&wgpu::Surface>,
+) -> anyhow::Result<wgpu::Adapter> {
+    for force_fallback_adapter in [false, true] {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface,
+                force_fallback_adapter,
+            })
+            .await;
+        match adapter {
+            Some(adapter) => return Ok(adapter),
+            None if !force_fallback_adapter => log::warn!("No GPU adapter found, trying the fallback adapter"),
+            None => {}
+        }
+    }
+    anyhow::bail!("No suitable GPU adapter was found. Tried the backends: {:?}. \
+                   Check that the graphics drivers are installed and up to date", backends)
+}
+
+async fn request_device(adapter: &wgpu::Adapter) -> anyhow::Result<(wgpu::Device, wgpu::Queue)> {
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                // WebGL doesn't support all of wgpu's features, so if
+                // we're building for the web we'll have to disable some.
+                limits: if cfg!(target_arch = "wasm32") {
+                    wgpu::Limits::downlevel_webgl2_defaults()
+                } else {
+                    // The default bind group alone uses up the default limit of uniform buffers,
+                    // so take whatever the adapter allows for the modules with custom uniforms
+                    wgpu::Limits {
+                        max_uniform_buffers_per_shader_stage: adapter.limits().max_uniform_buffers_per_shader_stage,
+                        ..wgpu::Limits::default()
+                    }
+                },
+            },
+            None, // Trace path
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not open a device on the GPU adapter {:?}: {}", adapter.get_info().name, e))
+}
+
+// The stages the FFT bins storage buffer is bound to, vertex shaders can only read storage
+// buffers when the adapter supports it
+fn fft_bins_visibility(adapter: &wgpu::Adapter) -> wgpu::ShaderStages {
+    if adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::VERTEX_STORAGE) {
+        wgpu::ShaderStages::VERTEX_FRAGMENT
+    } else {
+        log::warn!("The GPU adapter can't read storage buffers in vertex shaders, FftBins are only available to fragment shaders");
+        wgpu::ShaderStages::FRAGMENT
+    }
+}
+
+// Picks the closest MSAA sample count to the requested one that the adapter can render with
+fn supported_sample_count(adapter: &wgpu::Adapter, format: wgpu::TextureFormat, requested: u32) -> u32 {
+    let sample_count = match requested {
+        0 | 1 => return 1,
+        2 | 4 | 8 => requested,
+        _ => {
+            log::warn!("MsaaSamples must be 1, 2, 4 or 8, got {}", requested);
+            requested.next_power_of_two().min(8)
+        }
+    };
+
+    let multisample = wgpu::TextureFormatFeatureFlags::MULTISAMPLE;
+    if !adapter.get_texture_format_features(format).flags.contains(multisample)
+        || !adapter.get_texture_format_features(texture::Texture::DEPTH_FORMAT).flags.contains(multisample) {
+        log::warn!("The adapter does not support MSAA, disabling it");
+        return 1;
+    }
+
+    // 4 samples is the only count every adapter is guaranteed to support
+    if sample_count != 4 && !adapter.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+        log::warn!("{}x MSAA may not be supported by the adapter, using 4x instead", sample_count);
+        return 4;
+    }
+
+    sample_count
+}
+
+// The MinWidth and MinHeight settings, at least 1 pixel so the aspect ratio is always defined
+fn min_window_size(settings: &Settings) -> LogicalSize<f32> {
+    LogicalSize::new(settings.min_width.max(1) as f32, settings.min_height.max(1) as f32)
+}
+
+// Moves the window to WindowX and WindowY on the monitor picked by MonitorIndex. The position is
+// in logical pixels from the top left of the monitor, and the window is centered on the monitor
+// when the position is off screen. Without any of the settings the OS picks the position
+fn place_window(window: &Window, settings: &Settings) {
+    if settings.window_x.is_none() && settings.window_y.is_none() && settings.monitor_index.is_none() {
+        return;
+    }
+
+    let monitor = match settings.monitor_index {
+        Some(index) => window.available_monitors().nth(index).or_else(|| {
+            log::warn!("There is no monitor {}, using the current monitor", index);
+            window.current_monitor()
+        }),
+        None => window.current_monitor(),
+    };
+    let monitor = match monitor {
+        Some(t) => t,
+        None => return,
+    };
+
+    // winit doesn't expose the work area, so the window is kept inside the whole monitor
+    let monitor_position = monitor.position();
+    let monitor_size = monitor.size();
+    let window_size = window.outer_size();
+    let max_x = monitor_size.width.saturating_sub(window_size.width) as i32;
+    let max_y = monitor_size.height.saturating_sub(window_size.height) as i32;
+
+    let scale_factor = monitor.scale_factor();
+    let requested_x = settings.window_x.map(|x| (x as f64 * scale_factor) as i32);
+    let requested_y = settings.window_y.map(|y| (y as f64 * scale_factor) as i32);
+    let on_screen = |position: Option<i32>, size: u32| match position {
+        Some(t) => t >= 0 && t < size as i32,
+        None => true,
+    };
+
+    let (x, y) = if on_screen(requested_x, monitor_size.width) && on_screen(requested_y, monitor_size.height) {
+        (requested_x.unwrap_or(max_x / 2).min(max_x), requested_y.unwrap_or(max_y / 2).min(max_y))
+    } else {
+        log::warn!("WindowX and WindowY are off screen, centering the window");
+        (max_x / 2, max_y / 2)
+    };
+
+    window.set_outer_position(PhysicalPosition::new(monitor_position.x + x, monitor_position.y + y));
+}
+
+// Number of floats the MIDI uniform holds, none without the midi feature
+fn midi_input_count(settings: &Settings) -> usize {
+    #[cfg(feature = "midi")]
+    {
+        settings.midi_inputs.len()
+    }
+    #[cfg(not(feature = "midi"))]
+    {
+        let _ = settings;
+        0
+    }
+}
+
+// Exponentially blends the latest FFT magnitudes into the smoothed ones. The smoothing is the
+// share kept per 60th of a second, so it looks the same at any frame rate
+fn smooth_fft(smoothed: &mut Vec<f32>, latest: &[f32], smoothing: f32, dt: Duration) {
+    let keep = smoothing.clamp(0.0, 1.0).powf(dt.as_secs_f32() * 60.0);
+    smoothed.resize(latest.len(), 0.0);
+    for (smoothed, latest) in smoothed.iter_mut().zip(latest) {
+        *smoothed = *smoothed * keep + latest * (1.0 - keep);
+    }
+}
+
+// Converts the MaxFps setting into the time a frame needs to take, a cap of 0 is ignored
+fn min_frame_time(settings: &Settings) -> Option<Duration> {
+    settings.max_fps
+        .filter(|max_fps| *max_fps > 0)
+        .map(|max_fps| Duration::from_secs_f64(1.0 / max_fps as f64))
+}
+
+// Reads the clear color from the settings as RGB or RGBA, falling back to transparent black.
+// A premultiplied surface needs the color premultiplied too, or a see through background glows
+fn background_color(settings: &Settings, alpha_mode: wgpu::CompositeAlphaMode) -> wgpu::Color {
+    let color = match settings.background_color[..] {
+        [r, g, b, a, ..] => wgpu::Color { r: r as f64, g: g as f64, b: b as f64, a: a as f64 },
+        [r, g, b] => wgpu::Color { r: r as f64, g: g as f64, b: b as f64, a: 1.0 },
+        _ => {
+            log::warn!("BackgroundColor needs at least 3 elements, using transparent black");
+            wgpu::Color::TRANSPARENT
+        }
+    };
+    if alpha_mode == wgpu::CompositeAlphaMode::PreMultiplied {
+        wgpu::Color { r: color.r * color.a, g: color.g * color.a, b: color.b * color.a, a: color.a }
+    } else {
+        color
     }
 }
 
+// The background image is added first so that the avatar modules draw over it. There's nothing
+// to draw it over when the window is transparent, so it is skipped then
+fn add_background(state: &State, renderer: &mut Renderer, settings: &Settings) {
+    let background_image = match &settings.background_image {
+        Some(t) => t,
+        None => return,
+    };
+    if settings.transparent_background {
+        eprintln!("Warning: BackgroundImage is ignored while TransparentBackground is on");
+        return;
+    }
+    match background::BackgroundBatch::new(state, background_image) {
+        Ok(t) => renderer.add_render_batch(Box::new(t)),
+        Err(e) => eprintln!("Warning: Could not load background image {}: {}", background_image, e),
+    }
+}
+
+// Renders a single frame of the avatar into a PNG without opening a window, for thumbnails of
+// avatar presets. No audio is captured, so the audio uniforms are all 0
+pub async fn render_frame(opt: &Opt, settings: &Settings, path: &Path, width: u32, height: u32) -> anyhow::Result<()> {
+    let mut state = State::new_headless(width, height, settings).await?;
+    let mut renderer = Renderer::new();
+
+    add_background(&state, &mut renderer, settings);
+
+    let avatar_presets = AvatarPresets::new(&opt.avatar, &settings.avatar_presets)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    avatar_presets.add_to_renderer(&state, &mut renderer);
+
+    state.update(Duration::ZERO);
+    renderer.update(&state);
+    let frame = renderer.render_offscreen(&state);
+    frame.save_png(&state.device, state.config.format, state.transparent_background, path)
+}
+
 #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
-pub async fn run(settings: &Settings) {
+pub async fn run(opt: &Opt, settings: &Settings) {
+    // Native builds set up the logger in main, before the settings are loaded
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             std::panic::set_hook(Box::new(console_error_panic_hook::hook));
             console_log::init_with_level(log::Level::Warn).expect("Could't initialize logger");
-        } else {
-            env_logger::init();
         }
     }
 
     let event_loop = EventLoop::new();
 
+    let icon = load_icon();
     let mut window = WindowBuilder::new()
-        .with_decorations(false)
+        // Decorated while it takes mouse input, the same as after RShift switches it
+        .with_decorations(!settings.click_through)
         .with_transparent(settings.transparent_background)
         .with_always_on_top(settings.always_on_top)
+        .with_resizable(settings.resizable)
+        // Logical pixels so the window is the same size on screens with different scale factors
         .with_inner_size(LogicalSize::new(settings.default_width as f32, settings.default_height as f32))
+        .with_min_inner_size(min_window_size(settings))
         .with_title(&settings.title)
-        .with_taskbar_icon(Some(load_icon()))
-        .with_window_icon(Some(load_icon()))
+        .with_taskbar_icon(icon.clone())
+        .with_window_icon(icon)
         .build(&event_loop)
         .unwrap();
     
-    window.set_cursor_hittest(false).expect("TODO: panic message");
+    place_window(&window, settings);
+
+    // Not every platform can make a window click through, it just stays clickable there
+    if let Err(e) = window.set_cursor_hittest(!settings.click_through) {
+        log::warn!("Could not change whether the window takes mouse input: {}", e);
+    }
 
     #[cfg(target_arch = "wasm32")]
     {
@@ -355,14 +1073,52 @@ pub async fn run(settings: &Settings) {
     }
 
     // State::new uses async code, so we're going to wait for it to finish
-    let mut state = State::new(&window, settings).await;
+    let mut state = match State::new(&window, settings).await {
+        Ok(t) => t,
+        Err(e) => crate::exit_with_error(e),
+    };
     let mut renderer = Renderer::new();
-    let mut last_render_time = Instant::now();
 
-    let avatar: avatar::Avatar = avatar_generator::build_avatar(avatar_generator::load_avatar_data().unwrap(), &state);
-    for avatar_module in avatar.avatar_modules.into_iter() {
-        renderer.add_render_batch(Box::new(avatar_module));
+    add_background(&state, &mut renderer, settings);
+    if let Some(path) = &opt.record {
+        match recorder::Recorder::new(path, opt.duration, opt.record_fps) {
+            Ok(t) => renderer.start_recording(t),
+            Err(e) => log::error!("Could not record to {}: {}", path.display(), e),
+        }
     }
+    let exit_after_recording = opt.record.is_some();
+    let mut audio_simulator = opt.simulate_audio.map(|pattern| AudioSimulator::new(pattern, settings));
+    let mut last_render_time = Instant::now();
+
+    // The avatar data is kept around so it can be saved again after changing it at runtime
+    let mut avatar_presets = match AvatarPresets::new(&opt.avatar, &settings.avatar_presets) {
+        Ok(t) => t,
+        Err(e) => crate::exit_with_error(e),
+    };
+    avatar_presets.add_to_renderer(&state, &mut renderer);
+    renderer.add_render_batch(Box::new(grid::GridBatch::new(&state)));
+
+    let settings_watcher = match SettingsWatcher::new(&opt.settings) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            log::warn!("Could not watch {} for changes: {}", opt.settings.display(), e);
+            None
+        }
+    };
+
+    // The connection has to stay alive for as long as the event loop runs
+    #[cfg(feature = "midi")]
+    let _midi_connection = if settings.midi_inputs.is_empty() {
+        None
+    } else {
+        match crate::midi::connect(settings.midi_port.as_deref(), settings.midi_inputs.clone()) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                log::warn!("Could not connect to MIDI input: {}", e);
+                None
+            }
+        }
+    };
 
     event_loop.run(move |event, _, control_flow| {
         match event {
@@ -370,28 +1126,36 @@ pub async fn run(settings: &Settings) {
                 ref event,
                 window_id,
             } if window_id == window.id() => {
-                window_events(&mut window, event);
+                window_events(&mut window, &mut state, &mut renderer, &mut avatar_presets, event);
                 if !state.input(event) {
 
                     match event {
                         WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                         WindowEvent::Resized(physical_size) => {
                             state.resize(*physical_size);
+                            renderer.resize(&state);
                         }
                         WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                             // new_inner_size is &mut so w have to dereference it twice
                             state.resize(**new_inner_size);
+                            renderer.resize(&state);
                         }
                         _ => {}
                     }
                 }
             }
-            Event::RedrawRequested(window_id) if window_id == window.id() => unsafe {
+            Event::RedrawRequested(window_id) if window_id == window.id() => {
                 let now = Instant::now();
                 let dt = now - last_render_time;
                 last_render_time = now;
+                if let Some(audio_simulator) = audio_simulator.as_mut() {
+                    audio_simulator.update(dt);
+                }
                 state.update(dt);
+                renderer.update(&state);
                 match renderer.render(&state) {
+                    // A --record run closes once the recording is done
+                    Ok(_) if exit_after_recording && !renderer.is_recording() => *control_flow = ControlFlow::Exit,
                     Ok(_) => {}
                     // Reconfigure the surface if it's lost or outdated
                     Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => state.resize(state.size),
@@ -402,6 +1166,22 @@ pub async fn run(settings: &Settings) {
                 }
             }
             Event::MainEventsCleared => {
+                // Apply any changes made to settings.json
+                if let Some(settings) = settings_watcher.as_ref().and_then(SettingsWatcher::poll) {
+                    state.apply_settings(&settings);
+                    window.set_min_inner_size(Some(min_window_size(&settings)));
+                }
+
+                // Wait until the next frame is due instead of spinning when the frame rate is capped
+                if let Some(min_frame_time) = state.min_frame_time {
+                    let next_frame = last_render_time + min_frame_time;
+                    if Instant::now() < next_frame {
+                        *control_flow = ControlFlow::WaitUntil(next_frame);
+                        return;
+                    }
+                }
+                *control_flow = ControlFlow::Poll;
+
                 // RedrawRequested will only trigger once, unless we manually
                 // request it.
                 window.request_redraw();
@@ -410,16 +1190,20 @@ pub async fn run(settings: &Settings) {
                 event,
                 ..
             } => {
-                device_events(&mut window, &event);
+                // Orbit the camera while the left mouse button is held
+                if let DeviceEvent::MouseMotion { delta } = event {
+                    if state.mouse_pressed {
+                        state.camera_controller.process_mouse(delta.0, delta.1);
+                    }
+                }
+                device_events(&mut window, &mut state, &event);
             }
             _ => {}
         }
     });
 }
 
-static mut TAKE_FOCUS: bool = true;
-
-fn device_events(window: &mut Window, event: &DeviceEvent) {
+fn device_events(window: &mut Window, state: &mut State, event: &DeviceEvent) {
     match event {
         DeviceEvent::Added => {}
         DeviceEvent::Removed => {}
@@ -429,12 +1213,20 @@ fn device_events(window: &mut Window, event: &DeviceEvent) {
         DeviceEvent::Button { .. } => {}
         DeviceEvent::Key(input) => {
             let is_pressed = input.state == ElementState::Pressed;
-            match input.virtual_keycode.unwrap() {
-                VirtualKeyCode::RShift => unsafe {
+            let key = match &input.virtual_keycode {
+                Some(t) => t,
+                None => return,
+            };
+            match key {
+                // Switch between a click through overlay and a normal window that can be moved. This
+                // is the one key that works without focus, a click through window can't be focused
+                VirtualKeyCode::RShift => {
                     if is_pressed {
-                        window.set_cursor_hittest(TAKE_FOCUS).expect("TODO: panic message");
-                        window.set_decorations(TAKE_FOCUS);
-                        TAKE_FOCUS = !TAKE_FOCUS;
+                        state.interactive = !state.interactive;
+                        if let Err(e) = window.set_cursor_hittest(state.interactive) {
+                            log::warn!("Could not change whether the window takes mouse input: {}", e);
+                        }
+                        window.set_decorations(state.interactive);
                     }
                 }
                 _ => {}
@@ -444,29 +1236,121 @@ fn device_events(window: &mut Window, event: &DeviceEvent) {
     }
 }
 
-fn window_events(window: &mut Window, event: &WindowEvent) {
+// Window events only arrive while the window has focus, so hotkeys are handled here rather than
+// as device events, which see every key pressed on the system
+fn window_events(window: &mut Window, state: &mut State, renderer: &mut Renderer, avatar_presets: &mut AvatarPresets, event: &WindowEvent) {
     match event {
         WindowEvent::KeyboardInput {
             input:
             KeyboardInput {
-                state,
+                state: key_state,
                 virtual_keycode: Some(keycode),
                 ..
             },
             ..
         } => {
-            // let is_pressed = *state == ElementState::Pressed;
+            let is_pressed = *key_state == ElementState::Pressed;
             match keycode {
                 VirtualKeyCode::LShift => {
 
+                }
+                // Save the avatar with the modules' current visibility
+                VirtualKeyCode::F6 => {
+                    if is_pressed {
+                        avatar_presets.avatar_data.update_visibility(renderer);
+                        match avatar_generator::save_avatar_data(&avatar_presets.avatar_data) {
+                            Ok(_) => println!("Saved avatar settings"),
+                            Err(e) => log::error!("Could not save avatar settings: {}", e),
+                        }
+                    }
+                }
+                // Pick a custom uniform of the avatar's modules and nudge it up or down
+                VirtualKeyCode::Tab => {
+                    if is_pressed {
+                        avatar_presets.select_next_custom_uniform();
+                    }
+                }
+                VirtualKeyCode::PageUp => {
+                    if is_pressed {
+                        avatar_presets.nudge_custom_uniform(1.0, state, renderer);
+                    }
+                }
+                VirtualKeyCode::PageDown => {
+                    if is_pressed {
+                        avatar_presets.nudge_custom_uniform(-1.0, state, renderer);
+                    }
+                }
+                // Show or hide the floor grid
+                VirtualKeyCode::G => {
+                    if is_pressed {
+                        let visible = !renderer.get_visible(grid::GRID_MODULE_NAME).unwrap_or(true);
+                        renderer.set_visible(grid::GRID_MODULE_NAME, visible);
+                    }
+                }
+                // Number keys show and hide the first 9 modules of the avatar, in the order they are listed
+                VirtualKeyCode::Key1 | VirtualKeyCode::Key2 | VirtualKeyCode::Key3 |
+                VirtualKeyCode::Key4 | VirtualKeyCode::Key5 | VirtualKeyCode::Key6 |
+                VirtualKeyCode::Key7 | VirtualKeyCode::Key8 | VirtualKeyCode::Key9 => {
+                    if is_pressed {
+                        avatar_presets.toggle_module_visible(*keycode as usize - VirtualKeyCode::Key1 as usize, renderer);
+                    }
+                }
+                // Switch between the avatar presets
+                VirtualKeyCode::LBracket => {
+                    if is_pressed {
+                        avatar_presets.cycle(-1, state, renderer);
+                    }
+                }
+                VirtualKeyCode::RBracket => {
+                    if is_pressed {
+                        avatar_presets.cycle(1, state, renderer);
+                    }
+                }
+                // Put the camera back to its starting or home position
+                VirtualKeyCode::Home => {
+                    if is_pressed {
+                        state.camera_controller.reset();
+                    }
+                }
+                // Let the window float above everything else, or go behind other windows again
+                VirtualKeyCode::F7 => {
+                    if is_pressed {
+                        state.always_on_top = !state.always_on_top;
+                        window.set_always_on_top(state.always_on_top);
+                    }
+                }
+                VirtualKeyCode::F12 => {
+                    if is_pressed {
+                        if let Err(e) = std::fs::create_dir_all(&state.screenshot_dir) {
+                            log::error!("Could not create screenshot directory: {}", e);
+                        }
+                        renderer.request_screenshot(screenshot::screenshot_path(&state.screenshot_dir));
+                    }
+                }
+                // Recompile the shaders of every module without rebuilding the avatar
+                VirtualKeyCode::F5 => {
+                    if is_pressed {
+                        renderer.reload_shaders(state);
+                    }
+                }
+                // Pause/resume the shader time, the camera keeps moving
+                VirtualKeyCode::Pause => {
+                    if is_pressed {
+                        state.paused = !state.paused;
+                    }
                 }
                 _ => {}
             }
         },
+        // Left dragging orbits the camera so the window is moved with the right mouse button
         WindowEvent::MouseInput {
+            button: MouseButton::Right,
+            state: ElementState::Pressed,
             ..
         } => {
-            window.drag_window().expect("TODO: panic message");
+            if let Err(e) = window.drag_window() {
+                log::warn!("Could not drag the window: {}", e);
+            }
         }
         _ => {}
     };
@@ -474,16 +1358,27 @@ fn window_events(window: &mut Window, event: &WindowEvent) {
 
 const ICON_IMAGE_PATH: &str = "sound_guy_icon.png";
 
-fn load_icon() -> Icon {
+// The window keeps the platform's default icon when the image can't be loaded
+fn load_icon() -> Option<Icon> {
     let (icon_rgba, icon_width, icon_height) = {
-        let image = image::open(ICON_IMAGE_PATH)
-            .expect("Failed to open icon path")
-            .into_rgba8();
+        let image = match image::open(crate::asset_path(ICON_IMAGE_PATH)) {
+            Ok(t) => t.into_rgba8(),
+            Err(e) => {
+                log::warn!("Could not open icon {}: {}", ICON_IMAGE_PATH, e);
+                return None;
+            }
+        };
         let (width, height) = image.dimensions();
         let rgba = image.into_raw();
         (rgba, width, height)
     };
 
-    Icon::from_rgba(icon_rgba, icon_width, icon_height).unwrap()
+    match Icon::from_rgba(icon_rgba, icon_width, icon_height) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            log::warn!("Could not use {} as the icon: {}", ICON_IMAGE_PATH, e);
+            None
+        }
+    }
 }
 
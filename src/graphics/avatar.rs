@@ -1,6 +1,10 @@
-use wgpu::{Buffer, RenderPipeline};
+use std::rc::Rc;
+use wgpu::{BindGroup, Buffer, PipelineLayout, RenderPipeline};
+use crate::graphics::avatar_generator::{AnimatedInstances, PipelineOptions, read_shader_source, try_build_render_pipeline, WaveformMesh};
+use crate::graphics::custom_uniforms::CustomUniforms;
 use crate::graphics::model::{Mesh, Vertex};
 use crate::graphics::renderer::{RenderBatch};
+use crate::graphics::State;
 
 pub struct Avatar {
     pub(crate) avatar_modules: Vec<AvatarModule>,
@@ -10,17 +14,47 @@ pub struct AvatarModule {
     pub(crate) module_name: String,
     pub(crate) visible: bool,
     pub(crate) mesh: Mesh,
-    pub(crate) render_pipeline: RenderPipeline,
+    // Shared between modules built from the same shader, see build_avatar
+    pub(crate) render_pipeline: Rc<RenderPipeline>,
+    // Everything needed to rebuild the pipeline when the shader file is reloaded
+    pub(crate) render_pipeline_layout: Rc<PipelineLayout>,
+    pub(crate) pipeline_options: PipelineOptions,
+    // None for modules using one of the built in shaders
+    pub(crate) shader_file: Option<String>,
+    // Set for meshes that follow the audio input, which are rebuilt every frame
+    pub(crate) waveform: Option<WaveformMesh>,
+    // Bind group with only the uniforms the module asked for, None to use the default bind group
+    pub(crate) bind_group: Option<Rc<BindGroup>>,
+    // Bound to group 3 when the module declares its own uniforms, shared with its wireframe overlay
+    pub(crate) custom_uniforms: Option<Rc<CustomUniforms>>,
     pub(crate) vertex_buffer: Buffer,
     pub(crate) index_buffer: Buffer,
-    pub(crate) instance_buffer: Buffer,
-    pub(crate) index_count: u16,
+    // Shared with the module's wireframe overlay, which draws the same instances
+    pub(crate) instance_buffer: Rc<Buffer>,
+    pub(crate) index_count: u32,
     pub(crate) instance_count: u16,
+    // Set for instances that move on their own, their buffer is rewritten every frame
+    pub(crate) animated_instances: Option<AnimatedInstances>,
 }
 
 impl RenderBatch for AvatarModule {
     fn get_pipeline(&self) -> Option<&RenderPipeline> {
-        Some(&self.render_pipeline)
+        Some(&*self.render_pipeline)
+    }
+
+    fn get_bind_group(&self) -> Option<&BindGroup> {
+        self.bind_group.as_deref()
+    }
+
+    fn get_custom_bind_group(&self) -> Option<&BindGroup> {
+        self.custom_uniforms.as_ref().map(|custom_uniforms| &custom_uniforms.bind_group)
+    }
+
+    fn set_custom_uniform(&self, state: &State, name: &str, value: &[f32]) -> bool {
+        match &self.custom_uniforms {
+            Some(custom_uniforms) => custom_uniforms.write(&state.queue, name, value),
+            None => false,
+        }
     }
 
     fn get_vertex_buffer(&self) -> &Buffer {
@@ -35,12 +69,12 @@ impl RenderBatch for AvatarModule {
         &self.mesh.vertices[..]
     }
 
-    fn get_indices(&self) -> &[u16] {
+    fn get_indices(&self) -> &[u32] {
         &self.mesh.indices[..]
     }
 
     fn get_indices_count(&self) -> u32 {
-        self.index_count as u32
+        self.index_count
     }
 
     fn get_instance_buffer(&self) -> Option<&Buffer> {
@@ -54,4 +88,47 @@ impl RenderBatch for AvatarModule {
     fn get_visible(&self) -> bool {
         self.visible
     }
+
+    fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    fn get_module_name(&self) -> &str {
+        &self.module_name
+    }
+
+    fn update(&mut self, state: &State) {
+        if let Some(waveform) = self.waveform.as_mut() {
+            self.mesh = waveform.update();
+            state.queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.mesh.vertices[..]));
+        }
+        if let Some(animated_instances) = self.animated_instances.as_ref() {
+            let instance_data = animated_instances.update(state.time);
+            state.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        }
+    }
+
+    // Keeps the old pipeline when the new shader doesn't compile
+    fn reload_shader(&mut self, state: &State) {
+        let shader_file = match &self.shader_file {
+            Some(t) => t,
+            None => return,
+        };
+        let shader_source = match read_shader_source(shader_file) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Could not load shader {} of avatar module {:?}: {}", shader_file, self.module_name, e);
+                return;
+            }
+        };
+
+        match try_build_render_pipeline(state, shader_source, &self.render_pipeline_layout, self.pipeline_options) {
+            Ok(t) => {
+                self.render_pipeline = Rc::new(t);
+                println!("Reloaded shader {} of avatar module {:?}", shader_file, self.module_name);
+            }
+            Err(e) => eprintln!("Could not reload shader {} of avatar module {:?}, keeping the old one: {}",
+                                shader_file, self.module_name, e),
+        }
+    }
 }
\ No newline at end of file
@@ -1,21 +1,33 @@
+use std::collections::HashMap;
 use std::fs;
-use cgmath::{Quaternion, Rotation3, Vector3};
-use crate::graphics::model::{InstanceRaw, Mesh, Vertex};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use cgmath::{InnerSpace, One, Quaternion, Rotation3, Vector3};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use noise::{NoiseFn, Perlin};
+use crate::graphics::model::{InstanceRaw, Mesh, position_normal, Vertex};
 use serde::*;
-use wgpu::PrimitiveTopology;
+use wgpu::{PrimitiveTopology, RenderPipeline};
 use wgpu::util::DeviceExt;
-use crate::graphics;
+use crate::{audio, graphics};
 use crate::graphics::avatar::{Avatar, AvatarModule};
 use crate::graphics::model::Instance;
+use crate::graphics::renderer::Renderer;
 use crate::graphics::model::mesh_generation::*;
+use crate::graphics::shader_include::resolve_includes;
+use crate::graphics::custom_uniforms::{CustomUniformData, CustomUniforms};
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct AvatarData {
     avatar_module_data: Vec<AvatarModuleData>,
+    // File the avatar was loaded from, saving writes back to it
+    #[serde(skip)]
+    path: PathBuf,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct AvatarModuleData {
     module_name: String,
@@ -23,203 +35,1117 @@ pub struct AvatarModuleData {
     shader_data: ShaderData,
     mesh_generation: MeshData,
     instancing: InstanceData,
+    // Turning these off lets a module such as a glow layer draw over everything in front of it.
+    // Both are on when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth_test: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth_write: Option<bool>,
+    // How the module's colors are combined with what's already drawn, Replace when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blend_mode: Option<BlendMode>,
+    // Which triangles are skipped, and which winding faces the front. Nothing is culled and
+    // counter clockwise is the front when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cull_mode: Option<CullMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    front_face: Option<FrontFace>,
+    // Draws the edges of a Triangles mesh as lines over it, white unless a color is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wireframe_overlay: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wireframe_color: Option<[f32; 3]>,
 }
 
-#[derive(Deserialize)]
+// Everything about a module's render pipeline besides the shader
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct PipelineOptions {
+    pub(crate) primitive_topology: PrimitiveTopology,
+    pub(crate) depth_test: bool,
+    pub(crate) depth_write: bool,
+    pub(crate) depth_bias: bool,
+    pub(crate) blend_mode: BlendMode,
+    pub(crate) cull_mode: CullMode,
+    pub(crate) front_face: FrontFace,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct ShaderData {
+    #[serde(skip_serializing_if = "Option::is_none")]
     shader_uniform: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     source_file: Option<String>,
+    // Values only this module's shader sees, see CustomUniforms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    custom_uniforms: Option<Vec<CustomUniformData>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct MeshData {
+    #[serde(skip_serializing_if = "Option::is_none")]
     mesh_gen_function: Option<MeshGenFunction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mesh_render_type: Option<MeshRenderType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     sample: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     mesh_color_function: Option<MeshColorFunction>,
+    // Color loaded models with the color function even when the file has its own vertex colors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    procedural_color: Option<bool>,
+    // Replaces the per vertex index the generator assigned, which shaders use to stagger animation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index_function: Option<IndexFunction>,
+    // Moves the vertices along their normals once when the mesh is built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mesh_displacement: Option<MeshDisplacement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<f32>,
+    // Radius of each point for the Points render type. Points are drawn as 1 pixel when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    point_size: Option<f32>,
+    // Width of each line for the Lines render type. Lines are drawn as 1 pixel when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_width: Option<f32>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct InstanceData {
+    #[serde(skip_serializing_if = "Option::is_none")]
     count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     position_x: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     position_y: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     position_z: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    // When set, Sphere instances get random orientations generated from this seed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     instance_rotation_function: Option<InstanceRotationFunction>,
+    // Tints each instance, the mesh colors are drawn unchanged when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_color_function: Option<InstanceColorFunction>,
+    // Spins and bobs every instance, each with its own phase, instead of leaving them in place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    animated: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "ShaderUniform")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ShaderUniforms {
-    Default, Audio, Time,
+    Default, Camera, Time, Audio, AudioBands, Osc, Midi, Beat, AudioLeft, AudioRight, Mouse, AudioPeak,
 }
 
-#[derive(Serialize, Deserialize)]
+impl ShaderUniforms {
+    fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "Default" => Ok(ShaderUniforms::Default),
+            "Camera" => Ok(ShaderUniforms::Camera),
+            "Time" => Ok(ShaderUniforms::Time),
+            "Audio" => Ok(ShaderUniforms::Audio),
+            "AudioBands" => Ok(ShaderUniforms::AudioBands),
+            "Osc" => Ok(ShaderUniforms::Osc),
+            "Midi" => Ok(ShaderUniforms::Midi),
+            "Beat" => Ok(ShaderUniforms::Beat),
+            "AudioLeft" => Ok(ShaderUniforms::AudioLeft),
+            "AudioRight" => Ok(ShaderUniforms::AudioRight),
+            "Mouse" => Ok(ShaderUniforms::Mouse),
+            "AudioPeak" => Ok(ShaderUniforms::AudioPeak),
+            _ => Err(format!("Unknown shader uniform \"{}\", expected one of Default, Camera, Time, Audio, \
+                AudioBands, Osc, Midi, Beat, AudioLeft, AudioRight, Mouse, AudioPeak", name)),
+        }
+    }
+
+    // Uniforms keep the same binding as in the default bind group so shaders work with either
+    fn binding(&self) -> u32 {
+        match self {
+            ShaderUniforms::Default => 0,
+            ShaderUniforms::Camera => 0,
+            ShaderUniforms::Time => 1,
+            ShaderUniforms::Audio => 2,
+            ShaderUniforms::AudioBands => 4,
+            ShaderUniforms::Osc => 5,
+            ShaderUniforms::Midi => 6,
+            ShaderUniforms::Beat => 7,
+            ShaderUniforms::AudioLeft => 8,
+            ShaderUniforms::AudioRight => 9,
+            ShaderUniforms::Mouse => 10,
+            ShaderUniforms::AudioPeak => 11,
+        }
+    }
+
+    fn buffer<'a>(&self, state: &'a graphics::State) -> &'a wgpu::Buffer {
+        let default_bind_group = &state.default_bind_group;
+        match self {
+            ShaderUniforms::Default | ShaderUniforms::Camera => &default_bind_group.camera_buffer,
+            ShaderUniforms::Time => &default_bind_group.time_buffer,
+            ShaderUniforms::Audio => &default_bind_group.audio_buffer,
+            ShaderUniforms::AudioBands => &default_bind_group.audio_bands_buffer,
+            ShaderUniforms::Osc => &default_bind_group.osc_buffer,
+            ShaderUniforms::Midi => &default_bind_group.midi_buffer,
+            ShaderUniforms::Beat => &default_bind_group.beat_buffer,
+            ShaderUniforms::AudioLeft => &default_bind_group.audio_left_buffer,
+            ShaderUniforms::AudioRight => &default_bind_group.audio_right_buffer,
+            ShaderUniforms::Mouse => &default_bind_group.mouse_buffer,
+            ShaderUniforms::AudioPeak => &default_bind_group.audio_peak_buffer,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "MeshGenFunction")]
 pub enum MeshGenFunction {
-    Fibonacci, Cube, Loaded {file: String},
+    Fibonacci, Cube, Loaded {file: String}, UvSphere {rings: usize, sectors: usize},
+    // glTF (.gltf or .glb) model, every primitive is merged into one mesh
+    Gltf {file: String},
+    // Oscilloscope of the most recent audio samples, regenerated every frame
+    Waveform {samples: usize},
+    // Cylinder along the Y axis, or a cone when top_radius is 0. top_radius defaults to radius
+    Cylinder {radius: f32, top_radius: Option<f32>, height: f32, segments: usize, capped: bool},
+    // Flat regular polygon in the XY plane, e.g. 6 sides for a hexagon
+    Polygon {sides: usize, radius: f32},
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
 #[serde(tag = "MeshRenderType")]
 pub enum MeshRenderType {
     Lines, Triangles, Points
 }
 
-#[derive(Serialize, Deserialize)]
+// Alpha blends by the alpha the shader returns, Additive adds the colors up so overlapping
+// parts glow brighter
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(tag = "BlendMode")]
+pub enum BlendMode {
+    Replace, Alpha, Additive,
+}
+
+impl BlendMode {
+    // Shaders return straight alpha, so the color is still weighted by the source alpha. On a
+    // premultiplied surface the alpha has to be blended the premultiplied way as well, or the
+    // compositor sees too little alpha at soft edges and they fringe
+    fn to_wgpu(self, alpha_mode: wgpu::CompositeAlphaMode) -> wgpu::BlendState {
+        match self {
+            BlendMode::Replace => wgpu::BlendState::REPLACE,
+            BlendMode::Alpha if alpha_mode == wgpu::CompositeAlphaMode::PreMultiplied => wgpu::BlendState {
+                color: wgpu::BlendState::ALPHA_BLENDING.color,
+                alpha: wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING.alpha,
+            },
+            BlendMode::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            // Not weighted by alpha, the example shader returns an alpha of 0
+            BlendMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent::OVER,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(tag = "CullMode")]
+pub enum CullMode {
+    None, Front, Back,
+}
+
+impl CullMode {
+    fn to_wgpu(self) -> Option<wgpu::Face> {
+        match self {
+            CullMode::None => None,
+            CullMode::Front => Some(wgpu::Face::Front),
+            CullMode::Back => Some(wgpu::Face::Back),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(tag = "FrontFace")]
+pub enum FrontFace {
+    Ccw, Cw,
+}
+
+impl FrontFace {
+    fn to_wgpu(self) -> wgpu::FrontFace {
+        match self {
+            FrontFace::Ccw => wgpu::FrontFace::Ccw,
+            FrontFace::Cw => wgpu::FrontFace::Cw,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "MeshColorFunction")]
 pub enum MeshColorFunction {
-    Rainbow, Black, White,
+    Rainbow, Black, White, Hsv {saturation: f32, value: f32}, Solid {color: [f32; 3]},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "InstanceColorFunction")]
+pub enum InstanceColorFunction {
+    // Hue offset by the instance's place in the instance list
+    Hue {saturation: f32, value: f32},
+    Solid {color: [f32; 3]},
+}
+
+// All index functions give values in the range 0..1
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "IndexFunction")]
+pub enum IndexFunction {
+    // Vertex order from the first to the last vertex
+    Sequential,
+    // Repeats every n vertices
+    Modulo {n: usize},
+    // Distance from the origin relative to the furthest vertex
+    Radial,
+    // Random per vertex, the same every build for a seed
+    Random {seed: Option<u64>},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "MeshDisplacement")]
+pub enum MeshDisplacement {
+    // Perlin noise sampled at each vertex position, so a sphere turns into a lumpy blob. A higher
+    // frequency gives smaller lumps, and the amplitude is the furthest a vertex moves
+    Noise {amplitude: f32, frequency: f32, seed: Option<u32>},
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "InstanceRotationFunction")]
 pub enum InstanceRotationFunction {
-    Default, Sphere,
+    Default, Sphere, Grid {rows: usize, cols: usize, spacing: f32}, Ring {radius: f32},
+}
+
+const POINT_SPRITE_SHADER: &str = include_str!("point_sprite.wgsl");
+const THICK_LINE_SHADER: &str = include_str!("thick_line.wgsl");
+
+// A waveform mesh keeps the mesh before quad expansion around, so that it can be moved to the
+// latest audio samples and expanded again every frame
+pub(crate) struct WaveformMesh {
+    base: Mesh,
+    quad_expansion: Option<QuadExpansion>,
+}
+
+impl WaveformMesh {
+    pub(crate) fn update(&mut self) -> Mesh {
+        let samples = audio::recent_samples(self.base.vertices.len());
+        for (vertex, sample) in self.base.vertices.iter_mut().zip(samples) {
+            vertex.position[1] = sample;
+        }
+        match self.quad_expansion {
+            Some(quad_expansion) => quad_expansion.expand(&self.base),
+            None => self.base.clone(),
+        }
+    }
+}
+
+// How far animated instances bob up and down, relative to their scale, and how fast they spin
+const INSTANCE_BOB_HEIGHT: f32 = 0.25;
+const INSTANCE_SPIN_SPEED: f32 = 0.5;
+
+// Animated instances keep the instances they were generated as, and are moved away from them by
+// the time every frame
+pub(crate) struct AnimatedInstances {
+    base: Vec<Instance>,
+}
+
+impl AnimatedInstances {
+    pub(crate) fn update(&self, time: f32) -> Vec<InstanceRaw> {
+        let instance_count = self.base.len();
+        self.base.iter().enumerate().map(|(index, instance)| {
+            let phase = index as f32 / instance_count as f32 * 2.0 * std::f32::consts::PI;
+            Instance {
+                position: instance.position
+                    + Vector3::unit_y() * (time + phase).sin() * INSTANCE_BOB_HEIGHT * instance.scale,
+                rotation: instance.rotation * Quaternion::from_angle_y(cgmath::Rad(time * INSTANCE_SPIN_SPEED + phase)),
+                scale: instance.scale,
+                color: instance.color,
+            }.to_raw()
+        }).collect()
+    }
 }
 
-static AVATAR_DATA_PATH: &str = "avatar_settings.json";
-pub fn load_avatar_data() -> Result<AvatarData, String> {
+// Points and lines with a size are expanded into camera facing quads and drawn with a built in
+// shader, since wgpu can only draw them 1 pixel wide
+#[derive(Clone, Copy)]
+enum QuadExpansion {
+    Points {point_size: f32},
+    Lines {line_width: f32},
+}
+
+impl QuadExpansion {
+    fn from_mesh_data(render_type: MeshRenderType, mesh_data: &MeshData) -> Option<Self> {
+        match render_type {
+            MeshRenderType::Points => mesh_data.point_size.map(|point_size| QuadExpansion::Points {point_size}),
+            MeshRenderType::Lines => mesh_data.line_width.map(|line_width| QuadExpansion::Lines {line_width}),
+            MeshRenderType::Triangles => None,
+        }
+    }
+
+    fn expand(&self, mesh: &Mesh) -> Mesh {
+        match self {
+            QuadExpansion::Points {..} => expand_points_to_quads(mesh),
+            QuadExpansion::Lines {..} => expand_lines_to_quads(mesh),
+        }
+    }
+
+    fn shader_source(&self) -> String {
+        match self {
+            QuadExpansion::Points {point_size} => POINT_SPRITE_SHADER.replace("POINT_SIZE", &format!("{:?}", point_size)),
+            QuadExpansion::Lines {line_width} => THICK_LINE_SHADER.replace("LINE_WIDTH", &format!("{:?}", line_width)),
+        }
+    }
+}
+
+impl AvatarData {
+    // Copies the current visibility of every module out of the renderer, so saving keeps
+    // the modules that were shown or hidden at runtime
+    pub fn update_visibility(&mut self, renderer: &Renderer) {
+        for avatar_module_data in self.avatar_module_data.iter_mut() {
+            if let Some(visible) = renderer.get_visible(&avatar_module_data.module_name) {
+                avatar_module_data.visible = visible;
+            }
+        }
+    }
+
+    // Every shader and model file the modules refer to that can't be found, so a typo in a path
+    // is reported before anything is built instead of as a shader or model parse error
+    fn missing_files(&self) -> Vec<MissingFile> {
+        let mut missing = Vec::new();
+        for avatar_module_data in self.avatar_module_data.iter() {
+            let mesh_data = &avatar_module_data.mesh_generation;
+            let mut files: Vec<&String> = Vec::new();
+
+            // The built in quad shaders are used instead of the source file
+            let mesh_render_type = mesh_data.mesh_render_type.unwrap_or(MeshRenderType::Lines);
+            if QuadExpansion::from_mesh_data(mesh_render_type, mesh_data).is_none() {
+                // The default shader has a built in fallback
+                files.extend(avatar_module_data.shader_data.source_file.iter()
+                    .filter(|file| file.as_str() != DEFAULT_SHADER_PATH));
+            }
+            match &mesh_data.mesh_gen_function {
+                Some(MeshGenFunction::Loaded {file}) | Some(MeshGenFunction::Gltf {file}) => files.push(file),
+                _ => {}
+            }
+
+            for file in files {
+                if !crate::asset_path(file).exists() {
+                    missing.push(MissingFile {module_name: avatar_module_data.module_name.clone(), file: file.clone()});
+                }
+            }
+        }
+        missing
+    }
+
+    // Point sizes and line widths are written into the built in quad shaders as literals, so one
+    // that isn't a finite number would make the shader fail to compile
+    fn invalid_quad_sizes(&self) -> Vec<String> {
+        let mut invalid = Vec::new();
+        for avatar_module_data in self.avatar_module_data.iter() {
+            let mesh_data = &avatar_module_data.mesh_generation;
+            for (name, size) in [("PointSize", mesh_data.point_size), ("LineWidth", mesh_data.line_width)] {
+                if let Some(size) = size.filter(|size| !size.is_finite()) {
+                    invalid.push(format!("{} of module {:?} must be a finite number, got {}", name, avatar_module_data.module_name, size));
+                }
+            }
+        }
+        invalid
+    }
+}
+
+// A file an avatar module refers to that doesn't exist
+#[derive(Debug)]
+pub struct MissingFile {
+    pub module_name: String,
+    pub file: String,
+}
+
+impl std::fmt::Display for MissingFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file {} not found in module {:?}", self.file, self.module_name)
+    }
+}
+
+#[derive(Debug)]
+pub enum AvatarDataError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    MissingFiles(PathBuf, Vec<MissingFile>),
+    InvalidValues(PathBuf, Vec<String>),
+}
+
+impl std::fmt::Display for AvatarDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AvatarDataError::Io(path, e) => write!(f, "Could not load {}: {}", path.display(), e),
+            AvatarDataError::Parse(path, e) => write!(f, "{} is not well-formatted: {}", path.display(), e),
+            AvatarDataError::MissingFiles(path, missing_files) => {
+                write!(f, "{} refers to missing files:", path.display())?;
+                for missing_file in missing_files {
+                    write!(f, "\n    {}", missing_file)?;
+                }
+                Ok(())
+            }
+            AvatarDataError::InvalidValues(path, invalid_values) => {
+                write!(f, "{} has invalid values:", path.display())?;
+                for invalid_value in invalid_values {
+                    write!(f, "\n    {}", invalid_value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub const DEFAULT_AVATAR_DATA_PATH: &str = "avatar_settings.json";
+pub const DEFAULT_SHADER_PATH: &str = "shader.wgsl";
+// Replaced in every module shader with the AlphaCutoff setting, 0 for modules that aren't alpha blended
+const ALPHA_CUTOFF: &str = "ALPHA_CUTOFF";
+
+// Used when the default files are missing so a fresh clone starts without any setup
+const BUILT_IN_AVATAR_DATA: &str = include_str!("../../avatar_settings.json");
+const BUILT_IN_SHADER: &str = include_str!("../../shader.wgsl");
+
+// Reads a shader from the assets with its includes inlined, falling back to the built in shader
+// when the default one is missing
+pub(crate) fn read_shader_source(shader_file: &str) -> Result<String, String> {
+    let source = match fs::read_to_string(crate::asset_path(shader_file)) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && shader_file == DEFAULT_SHADER_PATH => {
+            eprintln!("Warning: No shader at {}, using the built in shader. Create it to override it", shader_file);
+            BUILT_IN_SHADER.to_string()
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    resolve_includes(&source, shader_file)
+}
+
+pub fn load_avatar_data(path: &Path) -> Result<AvatarData, AvatarDataError> {
     // Load file as string
-    let file = match fs::read_to_string(AVATAR_DATA_PATH) {
+    let file = match fs::read_to_string(path) {
         Ok(t) => {t}
-        Err(_) => {"Could not load file".to_string()}
+        // Only the default avatar falls back, a missing file that was asked for is still an error
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && path == Path::new(DEFAULT_AVATAR_DATA_PATH) => {
+            eprintln!("Warning: No avatar file at {}, using the built in avatar. Create it to override it", path.display());
+            BUILT_IN_AVATAR_DATA.to_string()
+        }
+        Err(e) => return Err(AvatarDataError::Io(path.to_path_buf(), e)),
     };
 
-    let json : AvatarData = serde_json::from_str(&file).expect("JSON was not well-formatted");
+    let mut json : AvatarData = match serde_json::from_str(&file) {
+        Ok(t) => {t}
+        Err(e) => return Err(AvatarDataError::Parse(path.to_path_buf(), e)),
+    };
+
+    let missing_files = json.missing_files();
+    if !missing_files.is_empty() {
+        return Err(AvatarDataError::MissingFiles(path.to_path_buf(), missing_files));
+    }
+    let invalid_quad_sizes = json.invalid_quad_sizes();
+    if !invalid_quad_sizes.is_empty() {
+        return Err(AvatarDataError::InvalidValues(path.to_path_buf(), invalid_quad_sizes));
+    }
+    json.path = path.to_path_buf();
     return Ok(json);
 }
 
+// The avatar files that can be switched between at runtime, and the data of the one being shown
+pub struct AvatarPresets {
+    paths: Vec<PathBuf>,
+    current: usize,
+    pub avatar_data: AvatarData,
+    // Which of the custom uniforms of all the modules the nudge keys change, in the order they
+    // are listed. The first one until another is selected
+    selected_uniform: Option<usize>,
+}
+
+impl AvatarPresets {
+    // The avatar that is loaded first is added to the presets if it isn't one of them already
+    pub fn new(avatar_path: &Path, presets: &[String]) -> Result<Self, AvatarDataError> {
+        let mut paths: Vec<PathBuf> = presets.iter().map(crate::asset_path).collect();
+        let current = match paths.iter().position(|path| path == avatar_path) {
+            Some(t) => t,
+            None => {
+                paths.insert(0, avatar_path.to_path_buf());
+                0
+            }
+        };
+
+        Ok(Self {
+            avatar_data: load_avatar_data(&paths[current])?,
+            paths,
+            current,
+            selected_uniform: None,
+        })
+    }
+
+    // Builds the current avatar and adds its modules to the renderer
+    pub fn add_to_renderer(&self, state: &graphics::State, renderer: &mut Renderer) {
+        let avatar = build_avatar(self.avatar_data.clone(), state);
+        for avatar_module in avatar.avatar_modules.into_iter() {
+            renderer.add_render_batch(Box::new(avatar_module));
+        }
+    }
+
+    // Replaces the avatar with the preset `offset` places further in the list, wrapping around.
+    // The current avatar stays when the next one can't be loaded
+    pub fn cycle(&mut self, offset: isize, state: &graphics::State, renderer: &mut Renderer) {
+        if self.paths.len() < 2 {
+            return;
+        }
+        let index = (self.current as isize + offset).rem_euclid(self.paths.len() as isize) as usize;
+        let avatar_data = match load_avatar_data(&self.paths[index]) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Could not switch avatar preset: {}", e);
+                return;
+            }
+        };
+
+        for avatar_module_data in self.avatar_data.avatar_module_data.iter() {
+            renderer.remove_render_batch(&avatar_module_data.module_name);
+            renderer.remove_render_batch(&wireframe_module_name(&avatar_module_data.module_name));
+        }
+        self.avatar_data = avatar_data;
+        self.current = index;
+        self.selected_uniform = None;
+        self.add_to_renderer(state, renderer);
+        println!("Switched to avatar preset {}", self.paths[index].display());
+    }
+
+    // Shows or hides the module at the index in the current avatar's module list, along with its
+    // wireframe overlay so the lines don't stay on screen without the fill
+    pub fn toggle_module_visible(&self, index: usize, renderer: &mut Renderer) {
+        let module_name = match self.avatar_data.avatar_module_data.get(index) {
+            Some(avatar_module_data) => &avatar_module_data.module_name,
+            None => return,
+        };
+        if let Some(visible) = renderer.get_visible(module_name).map(|visible| !visible) {
+            renderer.set_visible(module_name, visible);
+            renderer.set_visible(&wireframe_module_name(module_name), visible);
+            println!("{} is now {}", module_name, if visible { "visible" } else { "hidden" });
+        }
+    }
+
+    // Module name and data of every custom uniform of the current avatar
+    fn custom_uniforms_mut(&mut self) -> Vec<(&str, &mut CustomUniformData)> {
+        self.avatar_data.avatar_module_data.iter_mut()
+            .flat_map(|avatar_module_data| {
+                let module_name = avatar_module_data.module_name.as_str();
+                avatar_module_data.shader_data.custom_uniforms.iter_mut().flatten()
+                    .map(move |uniform| (module_name, uniform))
+            })
+            .collect()
+    }
+
+    // Selects the next custom uniform for the nudge keys, wrapping around
+    pub fn select_next_custom_uniform(&mut self) {
+        let selected_uniform = self.selected_uniform.map_or(0, |selected_uniform| selected_uniform + 1);
+        let uniforms = self.custom_uniforms_mut();
+        if uniforms.is_empty() {
+            println!("The avatar has no custom uniforms");
+            return;
+        }
+        let selected_uniform = selected_uniform % uniforms.len();
+        let (module_name, uniform) = &uniforms[selected_uniform];
+        println!("Selected custom uniform {} of {:?}: {:?}", uniform.name(), module_name, uniform.value());
+        self.selected_uniform = Some(selected_uniform);
+    }
+
+    // Moves the selected custom uniform by `steps` of its step, within its bounds. The new value
+    // is kept in the avatar data so saving writes it out
+    pub fn nudge_custom_uniform(&mut self, steps: f32, state: &graphics::State, renderer: &Renderer) {
+        let selected_uniform = self.selected_uniform.unwrap_or(0);
+        let mut uniforms = self.custom_uniforms_mut();
+        let (module_name, uniform) = match uniforms.get_mut(selected_uniform) {
+            Some(t) => t,
+            None => return,
+        };
+        uniform.nudge(steps);
+        if renderer.set_custom_uniform(state, module_name, uniform.name(), uniform.value()) {
+            println!("{} of {:?} is now {:?}", uniform.name(), module_name, uniform.value());
+        }
+    }
+}
+
+pub fn save_avatar_data(avatar_data: &AvatarData) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(avatar_data).map_err(|e| e.to_string())?;
+    fs::write(&avatar_data.path, json).map_err(|e| e.to_string())
+}
+
+// Builds a bind group containing only the uniforms the module asks for, along with how many
+// uniform buffers it has. Returns None when the module should use the default bind group, either
+// because it didn't list any or listed Default
+fn build_uniform_bind_group(shader_uniform: &Option<Vec<String>>, state: &graphics::State)
+    -> Result<Option<(wgpu::BindGroupLayout, wgpu::BindGroup, u32)>, String> {
+    let names = match shader_uniform {
+        None => return Ok(None),
+        Some(t) => t,
+    };
+
+    let mut uniforms: Vec<ShaderUniforms> = Vec::new();
+    for name in names {
+        let uniform = ShaderUniforms::from_name(name)?;
+        if uniform == ShaderUniforms::Default {
+            return Ok(None);
+        }
+        if !uniforms.contains(&uniform) {
+            uniforms.push(uniform);
+        }
+    }
+
+    let layout_entries: Vec<wgpu::BindGroupLayoutEntry> = uniforms.iter().map(|uniform| wgpu::BindGroupLayoutEntry {
+        binding: uniform.binding(),
+        visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }).collect();
+    let layout = state.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &layout_entries,
+        label: Some("module_bind_group_layout"),
+    });
+
+    let entries: Vec<wgpu::BindGroupEntry> = uniforms.iter().map(|uniform| wgpu::BindGroupEntry {
+        binding: uniform.binding(),
+        resource: uniform.buffer(state).as_entire_binding(),
+    }).collect();
+    let bind_group = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &layout,
+        entries: &entries,
+        label: Some("module_bind_group"),
+    });
+
+    Ok(Some((layout, bind_group, uniforms.len() as u32)))
+}
+
+// Same as create_pipeline_layout, but validation errors such as having more uniform buffers than
+// the device allows are returned instead of bringing down the device
+fn try_create_pipeline_layout(
+    state: &graphics::State,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+) -> Result<wgpu::PipelineLayout, String> {
+    state.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let render_pipeline_layout = state.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Render Pipeline Layout"),
+        bind_group_layouts,
+        push_constant_ranges: &[],
+    });
+    match pollster::block_on(state.device.pop_error_scope()) {
+        Some(e) => Err(e.to_string()),
+        None => Ok(render_pipeline_layout),
+    }
+}
+
 pub fn build_avatar(avatar_data: AvatarData, state: &graphics::State) -> Avatar {
     let mut avatar_modules : Vec<AvatarModule> = Vec::new();
+    // Modules on the default bind group that use the same shader and options share one pipeline.
+    // The cache only lives for this build, so rebuilding the avatar picks up edited shaders
+    let mut pipeline_cache: HashMap<(String, PipelineOptions), Rc<RenderPipeline>> = HashMap::new();
     for avatar_module_data in avatar_data.avatar_module_data {
         println!("Starting avatar module creation of {:?}", avatar_module_data.module_name);
 
         let shader_data = avatar_module_data.shader_data;
         let mesh_data = avatar_module_data.mesh_generation;
         let instance_data = avatar_module_data.instancing;
+
+        let mesh_render_type = mesh_data.mesh_render_type.unwrap_or(MeshRenderType::Lines);
+        let quad_expansion = QuadExpansion::from_mesh_data(mesh_render_type, &mesh_data);
+
+        // Uniforms, the built in quad shaders only need the camera from the default bind group
+        let shader_uniform = if quad_expansion.is_some() { None } else { shader_data.shader_uniform };
+        let uniform_bind_group = match build_uniform_bind_group(&shader_uniform, state) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Skipping avatar module {:?}: {}", avatar_module_data.module_name, e);
+                continue;
+            }
+        };
+        let custom_uniforms = match shader_data.custom_uniforms.as_deref().filter(|_| quad_expansion.is_none()) {
+            Some(uniform_data) => match CustomUniforms::new(&state.device, uniform_data) {
+                Ok(t) => Some(Rc::new(t)),
+                Err(e) => {
+                    eprintln!("Skipping avatar module {:?}: {}", avatar_module_data.module_name, e);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        // The custom uniforms take one more uniform buffer on top of the ones in group 0
+        let uniform_buffer_count = match &uniform_bind_group {
+            Some((_, _, count)) => *count,
+            None => graphics::DEFAULT_UNIFORM_BUFFER_COUNT,
+        } + custom_uniforms.is_some() as u32;
+        let max_uniform_buffers = state.device.limits().max_uniform_buffers_per_shader_stage;
+        if uniform_buffer_count > max_uniform_buffers {
+            eprintln!("Skipping avatar module {:?}: it uses {} uniform buffers but the GPU only allows {}, \
+                list fewer ShaderUniforms to make room for the custom uniforms",
+                      avatar_module_data.module_name, uniform_buffer_count, max_uniform_buffers);
+            continue;
+        }
+
         // Create mesh
-        let mut mesh = match mesh_data.mesh_gen_function.unwrap_or(MeshGenFunction::Fibonacci) {
-            MeshGenFunction::Fibonacci => {gen_fibonacci_mesh(mesh_data.sample.unwrap_or(25) as u32)},
-            MeshGenFunction::Cube => {gen_cube_mesh()},
-            MeshGenFunction::Loaded {file} => {load_mesh_from_file(file)}
+        let mesh_gen_function = mesh_data.mesh_gen_function.unwrap_or(MeshGenFunction::Fibonacci);
+        let is_waveform = matches!(mesh_gen_function, MeshGenFunction::Waveform {..});
+        let (mut mesh, has_colors) = match mesh_gen_function {
+            MeshGenFunction::Fibonacci => {(gen_fibonacci_mesh(mesh_data.sample.unwrap_or(25) as u32), false)},
+            MeshGenFunction::Cube => {(gen_cube_mesh(), false)},
+            MeshGenFunction::Loaded {file} => match load_mesh_from_file(file) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Skipping avatar module {:?}: {}", avatar_module_data.module_name, e);
+                    continue;
+                }
+            },
+            MeshGenFunction::Gltf {file} => match load_mesh_from_gltf(file) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Skipping avatar module {:?}: {}", avatar_module_data.module_name, e);
+                    continue;
+                }
+            },
+            MeshGenFunction::UvSphere {rings, sectors} => {(gen_uv_sphere_mesh(rings, sectors), false)}
+            MeshGenFunction::Waveform {samples} => {(gen_waveform_mesh(samples), false)}
+            MeshGenFunction::Cylinder {radius, top_radius, height, segments, capped} => {
+                (gen_cylinder_mesh(radius, top_radius.unwrap_or(radius), height, segments, capped), false)
+            }
+            MeshGenFunction::Polygon {sides, radius} => {
+                (gen_polygon_mesh(sides, radius, matches!(mesh_render_type, MeshRenderType::Lines)), false)
+            }
+        };
+        // Displaced before coloring so colors and indices based on position follow the new shape
+        if let Some(mesh_displacement) = mesh_data.mesh_displacement {
+            displace_mesh(mesh_displacement, &mut mesh, matches!(mesh_render_type, MeshRenderType::Triangles));
+        }
+        // Models that bring their own vertex colors keep them unless procedural coloring is forced
+        if !has_colors || mesh_data.procedural_color.unwrap_or(false) {
+            color_mesh(mesh_data.mesh_color_function.unwrap_or(MeshColorFunction::Rainbow), &mut mesh);
+        }
+        if let Some(index_function) = mesh_data.index_function {
+            index_mesh(index_function, &mut mesh);
+        }
+        let waveform = if is_waveform {
+            Some(WaveformMesh {base: mesh.clone(), quad_expansion})
+        } else {
+            None
         };
-        color_mesh(mesh_data.mesh_color_function.unwrap_or(MeshColorFunction::Rainbow), &mut mesh);
+        if let Some(quad_expansion) = quad_expansion {
+            mesh = quad_expansion.expand(&mesh);
+        }
 
 
         // Instances
         let instance_count = instance_data.count.unwrap_or(1);
-        let instances = generate_instances
+        let mut instances = generate_instances
             (instance_data.instance_rotation_function.unwrap_or(InstanceRotationFunction::Default), instance_count,
             instance_data.position_x.unwrap_or(0.0),
             instance_data.position_y.unwrap_or(0.0),
-            instance_data.position_z.unwrap_or(0.0));
+            instance_data.position_z.unwrap_or(0.0),
+            instance_data.scale.unwrap_or(1.0),
+            instance_data.seed);
+        if let Some(instance_color_function) = instance_data.instance_color_function {
+            color_instances(instance_color_function, &mut instances);
+        }
+        let animated = instance_data.animated.unwrap_or(false);
         let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        // Animated instances write their new positions into the buffer every frame
         let instance_buffer = state.device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Instance Buffer"),
                 contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX,
+                usage: if animated {
+                    wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
+                } else {
+                    wgpu::BufferUsages::VERTEX
+                },
             }
         );
 
 
-        // Load file source
-        let shader_source = match fs::read_to_string(shader_data.source_file.unwrap_or("shader.wgsl".to_string())) {
-            Ok(t) => {t}
-            Err(_) => {"Could not load file".to_string()}
-        };
 
-        // Shader
-        let shader = state.device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(shader_source.into()),
-        });
+        // Load file source, the built in shaders can't be reloaded so they don't keep a file
+        let (shader_file, shader_source) = match quad_expansion {
+            Some(quad_expansion) => {
+                if let Some(source_file) = &shader_data.source_file {
+                    eprintln!("Avatar module {:?}: PointSize and LineWidth draw with a built in shader, ignoring SourceFile {}",
+                              avatar_module_data.module_name, source_file);
+                }
+                (None, quad_expansion.shader_source())
+            }
+            None => {
+                let shader_file = shader_data.source_file.unwrap_or(DEFAULT_SHADER_PATH.to_string());
+                let shader_source = match read_shader_source(&shader_file) {
+                    Ok(t) => {t}
+                    Err(e) => {
+                        eprintln!("Skipping avatar module {:?}: could not load shader {}: {}",
+                                  avatar_module_data.module_name, shader_file, e);
+                        continue;
+                    }
+                };
+                (Some(shader_file), shader_source)
+            }
+        };
 
-        // Render Pipeline
-        let render_pipeline_layout =
-            state.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&state.default_bind_group.default_bind_group_layout],
-                push_constant_ranges: &[],
-            });
+        // Render Pipeline, with group 3 only for modules that have custom uniforms
+        let mut bind_group_layouts = vec![match &uniform_bind_group {
+            Some((layout, _, _)) => layout,
+            None => &state.default_bind_group.default_bind_group_layout,
+        }, &state.spectrogram.bind_group_layout, &state.fft_bins.bind_group_layout];
+        if let Some(custom_uniforms) = &custom_uniforms {
+            bind_group_layouts.push(&custom_uniforms.bind_group_layout);
+        }
+        let render_pipeline_layout = match try_create_pipeline_layout(state, &bind_group_layouts) {
+            Ok(t) => Rc::new(t),
+            Err(e) => {
+                eprintln!("Skipping avatar module {:?}: {}", avatar_module_data.module_name, e);
+                continue;
+            }
+        };
 
-        let primitive_topology = get_primitive_topology(mesh_data.mesh_render_type.unwrap_or(MeshRenderType::Lines));
+        let wireframe_overlay = avatar_module_data.wireframe_overlay.unwrap_or(false);
+        if wireframe_overlay && (!matches!(mesh_render_type, MeshRenderType::Triangles) || waveform.is_some()) {
+            eprintln!("Avatar module {:?}: WireframeOverlay only works on Triangles meshes, ignoring it",
+                      avatar_module_data.module_name);
+        }
+        let wireframe_overlay = wireframe_overlay && matches!(mesh_render_type, MeshRenderType::Triangles) && waveform.is_none();
 
-        let render_pipeline = state.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[Vertex::desc(), InstanceRaw::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: state.config.format,
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent::REPLACE,
-                        alpha: wgpu::BlendComponent::REPLACE,
-                    }),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: primitive_topology,
-                front_face: wgpu::FrontFace::Ccw,
-                ..Default::default()
+        let pipeline_options = PipelineOptions {
+            primitive_topology: match quad_expansion {
+                Some(_) => PrimitiveTopology::TriangleList,
+                None => get_primitive_topology(mesh_render_type),
             },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: crate::graphics::texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less, // 1.
-                stencil: wgpu::StencilState::default(), // 2.
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            // If the pipeline will be used with a multiview render pass, this
-            // indicates how many array layers the attachments will have.
-            multiview: None,
-        });
+            depth_test: avatar_module_data.depth_test.unwrap_or(true),
+            depth_write: avatar_module_data.depth_write.unwrap_or(true),
+            // Pushes the fill back a little so the overlay's lines win the depth test
+            depth_bias: wireframe_overlay,
+            blend_mode: avatar_module_data.blend_mode.unwrap_or(BlendMode::Replace),
+            cull_mode: avatar_module_data.cull_mode.unwrap_or(CullMode::None),
+            front_face: avatar_module_data.front_face.unwrap_or(FrontFace::Ccw),
+        };
 
+        // Built in shaders have no file, so they are keyed by their generated source. Modules with
+        // their own bind groups get their own pipelines
+        let has_own_bind_group = uniform_bind_group.is_some() || custom_uniforms.is_some();
+        let cache_key = |pipeline_options: PipelineOptions| if has_own_bind_group {
+            None
+        } else {
+            Some((shader_file.clone().unwrap_or_else(|| shader_source.clone()), pipeline_options))
+        };
+        // A broken shader only skips its own module instead of crashing the whole program
+        let render_pipeline = match get_or_build_render_pipeline(state, &mut pipeline_cache, cache_key(pipeline_options),
+                                                                 shader_source.clone(), &render_pipeline_layout, pipeline_options) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Skipping avatar module {:?}: shader failed to compile: {}", avatar_module_data.module_name, e);
+                continue;
+            }
+        };
+
+        // Waveforms write their new vertices into the buffer every frame
         let vertex_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Vertex Buffer"),
             contents: bytemuck::cast_slice(&mesh.vertices[..]),
-            usage: wgpu::BufferUsages::VERTEX,
+            usage: if waveform.is_some() {
+                wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
+            } else {
+                wgpu::BufferUsages::VERTEX
+            },
         });
         let index_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Index Buffer"),
             contents: bytemuck::cast_slice(&mesh.indices[..]),
             usage: wgpu::BufferUsages::INDEX,
         });
-        
+
+        let bind_group = uniform_bind_group.map(|(_, bind_group, _)| Rc::new(bind_group));
+        let instance_buffer = Rc::new(instance_buffer);
+        let instance_count = instances.len() as u16;
+
+        // The overlay is its own module drawn right after the fill, with the mesh's triangles
+        // turned into lines. It shares the fill's bind group and instances
+        let overlay = if wireframe_overlay {
+            let overlay_options = PipelineOptions {
+                primitive_topology: PrimitiveTopology::LineList,
+                depth_bias: false,
+                ..pipeline_options
+            };
+            match get_or_build_render_pipeline(state, &mut pipeline_cache, cache_key(overlay_options),
+                                               shader_source.clone(), &render_pipeline_layout, overlay_options) {
+                Ok(render_pipeline) => {
+                    let mut overlay_mesh = Mesh::new(mesh.vertices.clone(), wireframe_indices(&mesh.indices));
+                    color_mesh_solid_color(&mut overlay_mesh, avatar_module_data.wireframe_color.unwrap_or([1.0, 1.0, 1.0]));
+                    Some(AvatarModule {
+                        module_name: wireframe_module_name(&avatar_module_data.module_name),
+                        visible: avatar_module_data.visible,
+                        render_pipeline,
+                        render_pipeline_layout: render_pipeline_layout.clone(),
+                        pipeline_options: overlay_options,
+                        shader_file: shader_file.clone(),
+                        waveform: None,
+                        bind_group: bind_group.clone(),
+                        custom_uniforms: custom_uniforms.clone(),
+                        vertex_buffer: state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                            label: Some("Wireframe Vertex Buffer"),
+                            contents: bytemuck::cast_slice(&overlay_mesh.vertices[..]),
+                            usage: wgpu::BufferUsages::VERTEX,
+                        }),
+                        index_buffer: state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                            label: Some("Wireframe Index Buffer"),
+                            contents: bytemuck::cast_slice(&overlay_mesh.indices[..]),
+                            usage: wgpu::BufferUsages::INDEX,
+                        }),
+                        instance_buffer: instance_buffer.clone(),
+                        index_count: overlay_mesh.indices.len() as u32,
+                        mesh: overlay_mesh,
+                        instance_count,
+                        animated_instances: None,
+                    })
+                }
+                Err(e) => {
+                    eprintln!("Avatar module {:?}: wireframe overlay failed to compile: {}", avatar_module_data.module_name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         avatar_modules.push(AvatarModule {
             module_name: avatar_module_data.module_name,
             visible: avatar_module_data.visible,
             render_pipeline,
+            render_pipeline_layout,
+            pipeline_options,
+            shader_file,
+            waveform,
+            bind_group,
+            custom_uniforms,
             vertex_buffer,
             index_buffer,
             instance_buffer,
-            index_count: mesh.indices.len() as u16,
+            index_count: mesh.indices.len() as u32,
             mesh,
-            instance_count: instance_count as u16,
+            instance_count,
+            animated_instances: if animated { Some(AnimatedInstances {base: instances}) } else { None },
         });
+        avatar_modules.extend(overlay);
     }
     Avatar {
         avatar_modules,
     }
 }
 
+// Compiles the shader and builds the module's render pipeline with it
+pub(crate) fn build_render_pipeline(
+    state: &graphics::State,
+    shader_source: String,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    pipeline_options: PipelineOptions,
+) -> wgpu::RenderPipeline {
+    // Only alpha blended modules are cut off, the others often return an alpha of 0
+    let alpha_cutoff = if pipeline_options.blend_mode == BlendMode::Alpha { state.alpha_cutoff } else { 0.0 };
+    let shader_source = shader_source.replace(ALPHA_CUTOFF, &format!("{:?}", alpha_cutoff));
+    let shader = state.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Shader"),
+        source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+    });
+
+    state.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Render Pipeline"),
+        layout: Some(render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[Vertex::desc(), InstanceRaw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: state.config.format,
+                blend: Some(pipeline_options.blend_mode.to_wgpu(state.config.alpha_mode)),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: pipeline_options.primitive_topology,
+            front_face: pipeline_options.front_face.to_wgpu(),
+            cull_mode: pipeline_options.cull_mode.to_wgpu(),
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: crate::graphics::texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: pipeline_options.depth_write,
+            depth_compare: if pipeline_options.depth_test { wgpu::CompareFunction::Less } else { wgpu::CompareFunction::Always }, // 1.
+            stencil: wgpu::StencilState::default(), // 2.
+            bias: if pipeline_options.depth_bias {
+                wgpu::DepthBiasState {constant: 2, slope_scale: 1.0, clamp: 0.0}
+            } else {
+                wgpu::DepthBiasState::default()
+            },
+        }),
+        multisample: wgpu::MultisampleState {
+            count: state.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        // If the pipeline will be used with a multiview render pass, this
+        // indicates how many array layers the attachments will have.
+        multiview: None,
+    })
+}
+
+fn wireframe_module_name(module_name: &str) -> String {
+    format!("{} Wireframe", module_name)
+}
+
+// Reuses the pipeline built for the same key earlier in the build, modules without a key always
+// get a new one
+fn get_or_build_render_pipeline(
+    state: &graphics::State,
+    pipeline_cache: &mut HashMap<(String, PipelineOptions), Rc<RenderPipeline>>,
+    cache_key: Option<(String, PipelineOptions)>,
+    shader_source: String,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    pipeline_options: PipelineOptions,
+) -> Result<Rc<RenderPipeline>, String> {
+    if let Some(render_pipeline) = cache_key.as_ref().and_then(|key| pipeline_cache.get(key)) {
+        return Ok(render_pipeline.clone());
+    }
+    let render_pipeline = Rc::new(try_build_render_pipeline(state, shader_source, render_pipeline_layout, pipeline_options)?);
+    if let Some(key) = cache_key {
+        pipeline_cache.insert(key, render_pipeline.clone());
+    }
+    Ok(render_pipeline)
+}
+
+// Same as build_render_pipeline, but validation errors such as a typo in the shader are
+// returned instead of bringing down the device
+pub(crate) fn try_build_render_pipeline(
+    state: &graphics::State,
+    shader_source: String,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    pipeline_options: PipelineOptions,
+) -> Result<wgpu::RenderPipeline, String> {
+    state.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let render_pipeline = build_render_pipeline(state, shader_source, render_pipeline_layout, pipeline_options);
+    match pollster::block_on(state.device.pop_error_scope()) {
+        Some(e) => Err(e.to_string()),
+        None => Ok(render_pipeline),
+    }
+}
+
 fn get_primitive_topology(render_type: MeshRenderType) -> PrimitiveTopology {
     match render_type {
         MeshRenderType::Lines => {PrimitiveTopology::LineList}
@@ -228,7 +1154,7 @@ fn get_primitive_topology(render_type: MeshRenderType) -> PrimitiveTopology {
     }
 }
 
-fn generate_instances(instance_rotation_function: InstanceRotationFunction, index_count: usize, position_x: f32, position_y: f32, position_z: f32) -> Vec<Instance> {
+fn generate_instances(instance_rotation_function: InstanceRotationFunction, index_count: usize, position_x: f32, position_y: f32, position_z: f32, instance_scale: f32, seed: Option<u64>) -> Vec<Instance> {
     let mut instances: Vec<Instance> = Vec::new();
     match instance_rotation_function {
         InstanceRotationFunction::Default => {
@@ -239,19 +1165,67 @@ fn generate_instances(instance_rotation_function: InstanceRotationFunction, inde
                     z: 0.0,
                 },
                 rotation: Quaternion::from_axis_angle(Vector3::new(0.0,0.0,0.0), cgmath::Deg(45.0)),
+                scale: instance_scale,
+                color: [1.0, 1.0, 1.0],
             });
         }
         InstanceRotationFunction::Sphere => {
             let scale: f32 = 5.0;
             let points = fibonacci_sphere_points(index_count as u32);
+            // Seeded so that reloading the avatar gives the same orientations every time
+            let mut rng = seed.map(StdRng::seed_from_u64);
 
             for (x,y,z) in points.into_iter() {
                 let pos_x = x * scale + position_x;
                 let pos_y = y * scale + position_y;
                 let pos_z = z * scale + position_z;
+                let rotation = match rng.as_mut() {
+                    Some(rng) => Quaternion::from_axis_angle(
+                        Vector3::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5).normalize(),
+                        cgmath::Deg(rng.gen::<f32>() * 360.0)),
+                    None => Quaternion::from_axis_angle(Vector3::new(0.0,0.0,0.0), cgmath::Deg(45.0)),
+                };
                 instances.push(Instance {
                     position: Vector3 {x:pos_x , y:pos_y, z:pos_z},
-                    rotation: Quaternion::from_axis_angle(Vector3::new(0.0,0.0,0.0), cgmath::Deg(45.0))
+                    rotation,
+                    scale: instance_scale,
+                    color: [1.0, 1.0, 1.0],
+                });
+            }
+        }
+        InstanceRotationFunction::Grid {rows, cols, spacing} => {
+            // Center the grid on the module position
+            let offset_x = (cols as f32 - 1.0) * spacing / 2.0;
+            let offset_y = (rows as f32 - 1.0) * spacing / 2.0;
+
+            for index in 0..index_count.min(rows * cols) {
+                let row = index / cols;
+                let col = index % cols;
+                instances.push(Instance {
+                    position: Vector3 {
+                        x: col as f32 * spacing - offset_x + position_x,
+                        y: row as f32 * spacing - offset_y + position_y,
+                        z: position_z,
+                    },
+                    rotation: Quaternion::one(),
+                    scale: instance_scale,
+                    color: [1.0, 1.0, 1.0],
+                });
+            }
+        }
+        InstanceRotationFunction::Ring {radius} => {
+            for index in 0..index_count {
+                let angle = index as f32 / index_count as f32 * 2.0 * std::f32::consts::PI;
+                instances.push(Instance {
+                    position: Vector3 {
+                        x: angle.cos() * radius + position_x,
+                        y: position_y,
+                        z: angle.sin() * radius + position_z,
+                    },
+                    // Turn the instance's +Z axis to face away from the center of the ring
+                    rotation: Quaternion::from_angle_y(cgmath::Rad(std::f32::consts::FRAC_PI_2 - angle)),
+                    scale: instance_scale,
+                    color: [1.0, 1.0, 1.0],
                 });
             }
         }
@@ -260,6 +1234,18 @@ fn generate_instances(instance_rotation_function: InstanceRotationFunction, inde
     return instances;
 }
 
+fn color_instances(color_function: InstanceColorFunction, instances: &mut [Instance]) {
+    let instance_count = instances.len();
+    for (index, instance) in instances.iter_mut().enumerate() {
+        instance.color = match &color_function {
+            InstanceColorFunction::Hue {saturation, value} => {
+                hsv_to_rgb(index as f32 / instance_count as f32, *saturation, *value)
+            }
+            InstanceColorFunction::Solid {color} => *color,
+        };
+    }
+}
+
 fn color_mesh(color_function: MeshColorFunction, mesh: &mut Mesh) {
     match color_function {
         MeshColorFunction::Rainbow => {
@@ -271,11 +1257,104 @@ fn color_mesh(color_function: MeshColorFunction, mesh: &mut Mesh) {
         MeshColorFunction::White => {
             color_mesh_solid_color(mesh, [1.0,1.0,1.0])
         }
+        MeshColorFunction::Hsv {saturation, value} => {
+            color_mesh_hsv(mesh, saturation, value);
+        }
+        MeshColorFunction::Solid {color} => {
+            color_mesh_solid_color(mesh, color)
+        }
     }
 }
 
+fn index_mesh(index_function: IndexFunction, mesh: &mut Mesh) {
+    let vertex_count = mesh.vertices.len();
+    match index_function {
+        IndexFunction::Sequential => {
+            let last = vertex_count.saturating_sub(1).max(1) as f32;
+            for (index, vertex) in mesh.vertices.iter_mut().enumerate() {
+                vertex.index = index as f32 / last;
+            }
+        }
+        IndexFunction::Modulo {n} => {
+            let n = n.max(1);
+            let last = (n - 1).max(1) as f32;
+            for (index, vertex) in mesh.vertices.iter_mut().enumerate() {
+                vertex.index = (index % n) as f32 / last;
+            }
+        }
+        IndexFunction::Radial => {
+            let distance = |vertex: &Vertex| Vector3::from(vertex.position).magnitude();
+            let max_distance = mesh.vertices.iter().map(distance).fold(0.0, f32::max);
+            for vertex in mesh.vertices.iter_mut() {
+                vertex.index = if max_distance > 0.0 { distance(vertex) / max_distance } else { 0.0 };
+            }
+        }
+        IndexFunction::Random {seed} => {
+            let mut rng = StdRng::seed_from_u64(seed.unwrap_or(0));
+            for vertex in mesh.vertices.iter_mut() {
+                vertex.index = rng.gen_range(0.0..1.0);
+            }
+        }
+    }
+}
+
+// Triangle meshes get new normals to match their new shape
+fn displace_mesh(mesh_displacement: MeshDisplacement, mesh: &mut Mesh, triangles: bool) {
+    // Vertices without a normal would stay put, so they get one first. Lines and points have no
+    // faces to work it out from, so they move away from the origin instead
+    if mesh.vertices.iter().any(|vertex| vertex.normal == [0.0; 3]) {
+        if triangles {
+            mesh.compute_normals();
+        } else {
+            for vertex in mesh.vertices.iter_mut().filter(|vertex| vertex.normal == [0.0; 3]) {
+                vertex.normal = position_normal(vertex.position);
+            }
+        }
+    }
+
+    match mesh_displacement {
+        MeshDisplacement::Noise {amplitude, frequency, seed} => {
+            let perlin = Perlin::new(seed.unwrap_or(0));
+            for vertex in mesh.vertices.iter_mut() {
+                let position = Vector3::from(vertex.position);
+                let sample = position * frequency;
+                let offset = perlin.get([sample.x as f64, sample.y as f64, sample.z as f64]) as f32 * amplitude;
+                vertex.position = (position + Vector3::from(vertex.normal) * offset).into();
+            }
+        }
+    }
+    if triangles {
+        mesh.compute_normals();
+    }
+}
+
+// Colors each vertex by the angle of its position around the Y axis, giving a hue wheel
+fn color_mesh_hsv(mesh: &mut Mesh, saturation: f32, value: f32) {
+    for vertex in mesh.vertices.iter_mut() {
+        let angle = f32::atan2(vertex.position[2], vertex.position[0]);
+        let hue = (angle / (2.0 * std::f32::consts::PI)).rem_euclid(1.0);
+        vertex.color = hsv_to_rgb(hue, saturation, value);
+    }
+}
+
+// Hue, saturation, and value are all in the range 0..1
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+    let sector = hue * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    [r + m, g + m, b + m]
+}
+
 fn color_mesh_rainbow(mesh: &mut Mesh) {
-    mesh.vertices.len();
     for (index, mut vertex) in mesh.vertices.clone().into_iter().enumerate() {
         let r = (vertex.position[0] + 1.0) / 2.0;
         let g = (vertex.position[1] + 1.0) / 2.0;
@@ -283,7 +1362,9 @@ fn color_mesh_rainbow(mesh: &mut Mesh) {
         mesh.vertices[index].color = [r, g, b];
     }
     // Set the center color to black
-    mesh.vertices[0].color = [1.0,0.0,1.0];
+    if let Some(vertex) = mesh.vertices.first_mut() {
+        vertex.color = [1.0,0.0,1.0];
+    }
 }
 
 fn color_mesh_solid_color(mesh: &mut Mesh, color: [f32; 3]) {
@@ -298,11 +1379,14 @@ fn color_mesh_solid_color(mesh: &mut Mesh, color: [f32; 3]) {
 
 #[cfg(test)]
 pub mod test {
-    use crate::graphics::avatar_generator::{AvatarData, build_avatar, load_avatar_data};
+    use std::path::Path;
+    use cgmath::{InnerSpace, Vector3};
+    use crate::graphics::avatar_generator::{AvatarData, build_avatar, DEFAULT_AVATAR_DATA_PATH, displace_mesh, load_avatar_data, MeshColorFunction, MeshDisplacement};
+    use crate::graphics::model::mesh_generation::gen_fibonacci_mesh;
 
     #[test]
     fn test_load_avatar_data() {
-        match load_avatar_data() {
+        match load_avatar_data(Path::new(DEFAULT_AVATAR_DATA_PATH)) {
             Ok(t) => {
 
             }
@@ -311,4 +1395,36 @@ pub mod test {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_solid_color_from_json_list() {
+        let color_function: MeshColorFunction =
+            serde_json::from_str(r#"{"MeshColorFunction": "Solid", "color": [1.0, 0.5, 0.0]}"#).unwrap();
+        match color_function {
+            MeshColorFunction::Solid {color} => assert_eq!(color, [1.0, 0.5, 0.0]),
+            _ => panic!("Expected a solid color"),
+        }
+    }
+
+    #[test]
+    fn test_noise_displacement_moves_along_normals() {
+        let amplitude = 0.2;
+        let mesh = gen_fibonacci_mesh(50);
+        let mut displaced = mesh.clone();
+        displace_mesh(MeshDisplacement::Noise {amplitude, frequency: 1.5, seed: Some(3)}, &mut displaced, false);
+
+        for (vertex, displaced_vertex) in mesh.vertices.iter().zip(displaced.vertices.iter()) {
+            let offset = Vector3::from(displaced_vertex.position) - Vector3::from(vertex.position);
+            assert!(offset.magnitude() <= amplitude + 1e-5);
+            assert!(offset.cross(Vector3::from(vertex.normal)).magnitude() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_saved_avatar_data_loads_again() {
+        let avatar_data = load_avatar_data(Path::new(DEFAULT_AVATAR_DATA_PATH)).unwrap();
+        let json = serde_json::to_string_pretty(&avatar_data).unwrap();
+        let reloaded: AvatarData = serde_json::from_str(&json).unwrap();
+        assert_eq!(reloaded.avatar_module_data.len(), avatar_data.avatar_module_data.len());
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::*;
+use wgpu::{BindGroup, Buffer, RenderPipeline};
+use wgpu::util::DeviceExt;
+use crate::graphics::model::Vertex;
+use crate::graphics::renderer::RenderBatch;
+use crate::graphics::{State, texture};
+
+const BACKGROUND_SHADER: &str = include_str!("background.wgsl");
+
+// Fullscreen quad showing an image behind the avatar
+pub struct BackgroundBatch {
+    visible: bool,
+    render_pipeline: RenderPipeline,
+    bind_group: BindGroup,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    indices: Vec<u32>,
+    _texture: texture::Texture,
+}
+
+impl BackgroundBatch {
+    pub fn new(state: &State, image_path: &str) -> Result<Self> {
+        let image = image::open(crate::asset_path(image_path))?;
+        let texture = texture::Texture::from_image(&state.device, &state.queue, &image, Some(image_path))?;
+
+        let bind_group_layout = state.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("background_bind_group_layout"),
+        });
+
+        let bind_group = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("background_bind_group"),
+        });
+
+        let shader = state.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Background Shader"),
+            source: wgpu::ShaderSource::Wgsl(BACKGROUND_SHADER.into()),
+        });
+
+        let render_pipeline_layout = state.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Background Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let render_pipeline = state.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Background Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: state.config.format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                ..Default::default()
+            },
+            // Never write depth so the avatar always draws on top of the background
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: state.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
+        // Quad covering all of clip space
+        let vertices: Vec<Vertex> = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]].iter().map(|[x, y]| Vertex {
+            position: [*x, *y, 0.0],
+            color: [1.0, 1.0, 1.0],
+            index: 0.0,
+            normal: [0.0, 0.0, 1.0],
+        }).collect();
+        let indices: Vec<u32> = vec![0, 1, 2, 0, 2, 3];
+
+        let vertex_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Background Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vertices[..]),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Background Index Buffer"),
+            contents: bytemuck::cast_slice(&indices[..]),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Ok(Self {
+            visible: true,
+            render_pipeline,
+            bind_group,
+            vertex_buffer,
+            index_buffer,
+            indices,
+            _texture: texture,
+        })
+    }
+}
+
+impl RenderBatch for BackgroundBatch {
+    fn get_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.render_pipeline)
+    }
+
+    fn get_bind_group(&self) -> Option<&BindGroup> {
+        Some(&self.bind_group)
+    }
+
+    fn get_vertex_buffer(&self) -> &Buffer {
+        &self.vertex_buffer
+    }
+
+    fn get_index_buffer(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    // The quad never changes, so its vertices are only kept in the vertex buffer
+    fn get_vertices(&self) -> &[Vertex] {
+        &[]
+    }
+
+    fn get_indices(&self) -> &[u32] {
+        &self.indices[..]
+    }
+
+    fn get_indices_count(&self) -> u32 {
+        self.indices.len() as u32
+    }
+
+    fn get_instance_buffer(&self) -> Option<&Buffer> {
+        None
+    }
+
+    fn get_instance_count(&self) -> Option<u16> {
+        None
+    }
+
+    fn get_visible(&self) -> bool {
+        self.visible
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    fn get_module_name(&self) -> &str {
+        "Background"
+    }
+}
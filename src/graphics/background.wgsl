@@ -0,0 +1,28 @@
+// Draws the background image over the whole screen, behind every avatar module
+@group(0) @binding(0)
+var background_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var background_sampler: sampler;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(model: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(model.position.xy, 1.0, 1.0);
+    // Clip space has y pointing up while texture coordinates have it pointing down
+    out.uv = vec2<f32>(model.position.x + 1.0, 1.0 - model.position.y) / 2.0;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(background_texture, background_sampler, in.uv);
+}
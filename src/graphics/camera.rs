@@ -1,10 +1,10 @@
 use std::time::Duration;
-use cgmath::{EuclideanSpace, InnerSpace, Matrix4, MetricSpace, perspective, Point3, Quaternion, Rad, Rotation, Vector2, Vector3, VectorSpace, Zero};
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix4, MetricSpace, perspective, Point3, Quaternion, Rad, Rotation, Vector2, Vector3, VectorSpace, Zero};
 use cgmath::num_traits::{FloatConst, Pow};
 use rand::random;
 use winit::dpi::PhysicalPosition;
+use serde::Deserialize;
 use winit::event::{ElementState, KeyboardInput, MouseScrollDelta, VirtualKeyCode, WindowEvent};
-use crate::AUDIO_IN;
 use crate::graphics::camera;
 
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -37,20 +37,29 @@ impl Camera {
         }
     }
 
+    // Looks from the position in the direction given by the yaw and pitch. A yaw of 0 faces +X
+    // and -90 degrees faces -Z, a positive pitch looks up
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
 
         Matrix4::look_to_rh(
             self.position,
-            Vector3::new(
-                self.position.x * -1.0,
-                self.position.y * -1.0,
-                self.position.z * -1.0,
-            ).normalize(),
+            Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize(),
             Vector3::unit_y(),
         )
     }
+
+    // Turns the camera to face the target, keeping its position
+    pub fn look_at(&mut self, target: Point3<f32>) {
+        let direction = target - self.position;
+        if direction.magnitude2() == 0.0 {
+            return;
+        }
+        let direction = direction.normalize();
+        self.yaw = Rad(direction.z.atan2(direction.x));
+        self.pitch = Rad(direction.y.clamp(-1.0, 1.0).asin());
+    }
 }
 
 pub struct Projection {
@@ -68,16 +77,36 @@ impl Projection {
         znear: f32,
         zfar: f32,
     ) -> Self {
-        Self {
+        let mut projection = Self {
             aspect: width as f32 / height as f32,
             fovy: fovy.into(),
             znear,
             zfar,
-        }
+        };
+        projection.set_clip_planes(znear, zfar);
+        projection
     }
 
+    // The near plane has to be in front of the camera, and the far plane past the near plane
+    pub fn set_clip_planes(&mut self, znear: f32, zfar: f32) {
+        self.znear = znear.max(0.0001);
+        self.zfar = zfar.max(self.znear * 2.0);
+    }
+
+    // Only the aspect ratio changes on resize, the field of view is kept as is
     pub fn resize(&mut self, width: u32, height: u32) {
-        self.aspect = width as f32 / height as f32;
+        // A zero sized window would give an infinite or NaN aspect ratio
+        self.aspect = width.max(1) as f32 / height.max(1) as f32;
+    }
+
+    pub fn fovy(&self) -> Rad<f32> {
+        self.fovy
+    }
+
+    pub fn set_fov<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        let min: Rad<f32> = Deg(1.0).into();
+        let max: Rad<f32> = Deg(179.0).into();
+        self.fovy = Rad(fovy.into().0.clamp(min.0, max.0));
     }
 
     pub fn calc_matrix(&self) -> Matrix4<f32> {
@@ -85,6 +114,8 @@ impl Projection {
     }
 }
 
+// Matches the CameraUniform struct in the shaders. view_position is the world space position of
+// the camera with w = 1, padded to a vec4 for the uniform layout
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
@@ -108,49 +139,176 @@ impl CameraUniform {
     }
 }
 
+// How the camera moves around the avatar on its own
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum CameraRotationPath {
+    // Circles the avatar while bobbing up and down, so it sees it from every side
+    FullSphere,
+    // Circles the avatar at a fixed height
+    Horizontal,
+}
+
 pub struct CameraController {
     camera_target: Vector3<f32>,
 
     radius: f32,
+    min_radius: f32,
+    max_radius: f32,
     total_time: f32,
     speed: f32,
     sensitivity: f32,
 
+    // Manual orbit offsets from dragging the mouse, added on top of the automatic rotation
+    orbit_yaw: f32,
+    orbit_pitch: f32,
+    height_offset: f32,
+
+    // Keyboard input, accumulated so multiple keys can be held at once
+    amount_forward: f32,
+    amount_backward: f32,
+    amount_left: f32,
+    amount_right: f32,
+    amount_up: f32,
+    amount_down: f32,
+
     camera_rotation: bool,
+    rotation_speed: f32,
+    rotation_path: CameraRotationPath,
+    // Position the camera is reset to relative to the look target, see reset
+    home: Option<Vector3<f32>>,
+    // Center of the orbit, which the camera always faces
+    look_target: Vector3<f32>,
 }
 
+// Distance from the avatar the camera starts at
+const DEFAULT_RADIUS: f32 = 4.0;
+
+// Distance the camera moves for every line scrolled
+const SCROLL_ZOOM_STEP: f32 = 0.5;
+
+// Converts a scroll delta into lines, treating 100 pixels of a touchpad scroll as one line
+pub fn scroll_lines(delta: &MouseScrollDelta) -> f32 {
+    match delta {
+        MouseScrollDelta::LineDelta(_, scroll) => *scroll,
+        MouseScrollDelta::PixelDelta(PhysicalPosition { y: scroll, .. }) => *scroll as f32 / 100.0,
+    }
+}
+
+// Keep the pitch just short of the poles so the view never flips over
+const MAX_ORBIT_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
 impl CameraController {
     pub fn new(speed: f32, sensitivity: f32, camera_rotation: bool) -> Self {
         Self {
             camera_target: Vector3::new(1.0, 1.0, 1.0),
-            radius: 4.0,
+            radius: DEFAULT_RADIUS,
+            min_radius: 0.0,
+            max_radius: f32::MAX,
             total_time: 0.0,
             speed,
             sensitivity,
+            orbit_yaw: 0.0,
+            orbit_pitch: 0.0,
+            height_offset: 0.0,
+            amount_forward: 0.0,
+            amount_backward: 0.0,
+            amount_left: 0.0,
+            amount_right: 0.0,
+            amount_up: 0.0,
+            amount_down: 0.0,
             camera_rotation,
+            rotation_speed: 1.0,
+            rotation_path: CameraRotationPath::FullSphere,
+            home: None,
+            look_target: Vector3::zero(),
+        }
+
+    }
+
+    pub fn set_rotation(&mut self, speed: f32, path: CameraRotationPath) {
+        self.rotation_speed = speed;
+        self.rotation_path = path;
+    }
+
+    pub fn set_home(&mut self, home: Option<[f32; 3]>) {
+        self.home = home.map(Vector3::from);
+    }
+
+    // The radius is kept positive, so the camera can't pass through the look target and flip
+    pub fn set_radius_limits(&mut self, min_radius: f32, max_radius: f32) {
+        self.min_radius = min_radius.max(0.01);
+        self.max_radius = max_radius.max(self.min_radius);
+        self.radius = self.radius.clamp(self.min_radius, self.max_radius);
+    }
+
+    pub fn set_look_target(&mut self, look_target: [f32; 3]) {
+        self.look_target = Vector3::from(look_target);
+    }
+
+    // Undoes all of the zooming, orbiting and moving so the camera goes back to where it started,
+    // or to the home position when there is one
+    pub fn reset(&mut self) {
+        self.total_time = 0.0;
+        self.radius = DEFAULT_RADIUS.clamp(self.min_radius, self.max_radius);
+        self.orbit_yaw = 0.0;
+        self.orbit_pitch = 0.0;
+        self.height_offset = 0.0;
+
+        if let Some(home) = self.home {
+            if home.magnitude2() > 0.0 {
+                // Offset the orbit so the automatic rotation starts out at the home position
+                let base = self.auto_rotation_direction();
+                let home_direction = home.normalize();
+                self.radius = home.magnitude().clamp(self.min_radius, self.max_radius);
+                self.orbit_yaw = f32::atan2(home_direction.x, home_direction.z) - f32::atan2(base.x, base.z);
+                self.orbit_pitch = f32::asin(home_direction.y) - f32::asin(base.y);
+            }
         }
+    }
 
+    // Direction of the camera from the avatar before the mouse and keyboard offsets
+    fn auto_rotation_direction(&self) -> Vector3<f32> {
+        let time = if self.camera_rotation { self.total_time } else { 3.0 };
+        let height = match self.rotation_path {
+            CameraRotationPath::FullSphere => f32::sin(time / 2.0),
+            CameraRotationPath::Horizontal => 0.0,
+        };
+        Vector3::new(f32::sin(time), height, f32::cos(time)).normalize()
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity;
     }
 
     pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool{
         let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
         match key {
             VirtualKeyCode::W | VirtualKeyCode::Up => {
+                self.amount_forward = amount;
                 true
             }
             VirtualKeyCode::S | VirtualKeyCode::Down => {
+                self.amount_backward = amount;
                 true
             }
             VirtualKeyCode::A | VirtualKeyCode::Left => {
+                self.amount_left = amount;
                 true
             }
             VirtualKeyCode::D | VirtualKeyCode::Right => {
+                self.amount_right = amount;
                 true
             }
             VirtualKeyCode::Space => {
+                self.amount_up = amount;
                 true
             }
             VirtualKeyCode::LShift => {
+                self.amount_down = amount;
                 true
             }
             _ => false,
@@ -158,11 +316,14 @@ impl CameraController {
     }
 
     pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
-
+        // Sensitivity is in radians per 100 pixels of mouse movement
+        self.orbit_yaw -= mouse_dx as f32 * self.sensitivity * 0.01;
+        self.orbit_pitch = (self.orbit_pitch + mouse_dy as f32 * self.sensitivity * 0.01)
+            .clamp(-2.0 * MAX_ORBIT_PITCH, 2.0 * MAX_ORBIT_PITCH);
     }
 
     pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
-
+        self.radius = (self.radius - scroll_lines(delta) * SCROLL_ZOOM_STEP).clamp(self.min_radius, self.max_radius);
     }
 
     pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
@@ -170,16 +331,33 @@ impl CameraController {
 
 
         camera.position = Point3::from_vec(Vector3::lerp(camera.position.to_vec(), self.camera_target, self.speed * dt));
+        // The camera orbits the look target, so it always faces it
+        camera.look_at(Point3::from_vec(self.look_target));
+
+        // Move in and out, orbit left and right, and move up and down at `speed` units per second
+        self.radius = (self.radius + (self.amount_backward - self.amount_forward) * self.speed * dt)
+            .clamp(self.min_radius, self.max_radius);
+        self.orbit_yaw += (self.amount_right - self.amount_left) * self.speed * dt / self.radius;
+        self.height_offset += (self.amount_up - self.amount_down) * self.speed * dt;
+
+        self.camera_target = self.auto_rotation_direction();
 
-        self.camera_target.x = f32::sin(self.total_time);
-        self.camera_target.z = f32::cos(self.total_time);
-        self.camera_target.y = f32::sin(self.total_time / 2.0);
-        self.camera_target = self.camera_target.normalize() * self.radius;
+        // Combine the automatic rotation with the mouse orbit offsets
+        let yaw = f32::atan2(self.camera_target.x, self.camera_target.z) + self.orbit_yaw;
+        let pitch = (f32::asin(self.camera_target.y) + self.orbit_pitch)
+            .clamp(-MAX_ORBIT_PITCH, MAX_ORBIT_PITCH);
+        self.camera_target = Vector3::new(
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+            pitch.cos() * yaw.cos(),
+        ) * self.radius;
+        self.camera_target.y += self.height_offset;
+        self.camera_target += self.look_target;
 
 
         // Only update the time when the sphere is supposed to rotate
         if self.camera_rotation {
-            self.total_time = (self.total_time + dt) % (f32::PI() * 4.0);
+            self.total_time = (self.total_time + dt * self.rotation_speed).rem_euclid(f32::PI() * 4.0);
         } else {
             self.total_time = 3.0;
         }
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+// Fraction of the range between Min and Max that one nudge moves the value, when no step is given
+const NUDGE_FRACTION: f32 = 0.05;
+const DEFAULT_NUDGE_STEP: f32 = 0.1;
+
+// A value the module's JSON passes to its shader, a scalar or a vector of up to 4 components
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct CustomUniformData {
+    name: String,
+    value: Vec<f32>,
+    // Bounds every component is kept in when the value is nudged at runtime, and how far one
+    // nudge moves it. The step is a twentieth of the range, or 0.1 without both bounds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<f32>,
+}
+
+impl CustomUniformData {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &[f32] {
+        &self.value
+    }
+
+    // Moves every component by `steps` steps, staying within the bounds
+    pub fn nudge(&mut self, steps: f32) {
+        let step = match (self.step, self.min, self.max) {
+            (Some(step), _, _) => step,
+            (None, Some(min), Some(max)) => (max - min) * NUDGE_FRACTION,
+            _ => DEFAULT_NUDGE_STEP,
+        };
+        let min = self.min.unwrap_or(f32::NEG_INFINITY);
+        let max = self.max.unwrap_or(f32::INFINITY);
+        for component in self.value.iter_mut() {
+            *component = (*component + step * steps).max(min).min(max);
+        }
+    }
+}
+
+// Uniforms that belong to a single avatar module, so the same shader can be used by modules with
+// different constants. They are packed into one uniform buffer as the members of a struct, in
+// the order they are listed, so a module only takes one more uniform binding however many it has.
+// Shaders declare the struct in group 3 with each member as f32 or vecN<f32> to match its values:
+// struct CustomUniforms {
+//     spikiness: f32,
+//     tint: vec3<f32>,
+// };
+// @group(3) @binding(0)
+// var<uniform> custom: CustomUniforms;
+pub struct CustomUniforms {
+    buffer: wgpu::Buffer,
+    // Name, byte offset in the buffer and number of components of each uniform
+    layout: Vec<(String, u64, usize)>,
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) bind_group: wgpu::BindGroup,
+}
+
+// WGSL aligns f32 to 4 bytes, vec2 to 8 and vec3 and vec4 to 16
+fn component_alignment(components: usize) -> u64 {
+    match components {
+        1 => 4,
+        2 => 8,
+        _ => 16,
+    }
+}
+
+impl CustomUniforms {
+    pub fn new(device: &wgpu::Device, uniform_data: &[CustomUniformData]) -> Result<Self, String> {
+        let mut layout = Vec::new();
+        let mut size: u64 = 0;
+        for data in uniform_data {
+            if data.value.is_empty() || data.value.len() > 4 {
+                return Err(format!("custom uniform {:?} has {} values, expected 1 to 4", data.name, data.value.len()));
+            }
+            let alignment = component_alignment(data.value.len());
+            let offset = size.next_multiple_of(alignment);
+            size = offset + 4 * data.value.len() as u64;
+            layout.push((data.name.clone(), offset, data.value.len()));
+        }
+
+        // Uniform structs are padded to a multiple of 16 bytes
+        let mut contents = vec![0.0f32; size.max(1).next_multiple_of(16) as usize / 4];
+        for (data, (_, offset, _)) in uniform_data.iter().zip(layout.iter()) {
+            let start = *offset as usize / 4;
+            contents[start..start + data.value.len()].copy_from_slice(&data.value);
+        }
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Custom Uniforms Buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("custom_uniforms_bind_group_layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("custom_uniforms_bind_group"),
+        });
+
+        Ok(Self {
+            buffer,
+            layout,
+            bind_group_layout,
+            bind_group,
+        })
+    }
+
+    // Writes a new value for the uniform with the name, false when there's no such uniform or the
+    // value has a different number of components
+    pub fn write(&self, queue: &wgpu::Queue, name: &str, value: &[f32]) -> bool {
+        match self.layout.iter().find(|(uniform_name, _, _)| uniform_name == name) {
+            Some((_, offset, components)) if *components == value.len() => {
+                queue.write_buffer(&self.buffer, *offset, bytemuck::cast_slice(value));
+                true
+            }
+            _ => false,
+        }
+    }
+}
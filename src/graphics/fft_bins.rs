@@ -0,0 +1,66 @@
+use wgpu::util::DeviceExt;
+
+// FFT magnitudes evenly spaced in frequency, lowest first, in a storage buffer so shaders can
+// have far more of them than fit in a uniform. Shaders declare it as a runtime sized array:
+// @group(2) @binding(0)
+// var<storage, read> fft_bins: array<f32>;
+// Some downlevel backends can't read storage buffers in vertex shaders, there the bins are only
+// visible to fragment shaders
+pub struct FftBins {
+    buffer: wgpu::Buffer,
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) bind_group: wgpu::BindGroup,
+    bin_count: usize,
+}
+
+impl FftBins {
+    pub fn new(device: &wgpu::Device, bin_count: usize, visibility: wgpu::ShaderStages) -> Self {
+        let bin_count = bin_count.max(1);
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("FFT Bins Buffer"),
+            contents: bytemuck::cast_slice(&vec![0.0f32; bin_count]),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("fft_bins_bind_group_layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("fft_bins_bind_group"),
+        });
+
+        Self {
+            buffer,
+            bind_group_layout,
+            bind_group,
+            bin_count,
+        }
+    }
+
+    pub fn write(&self, queue: &wgpu::Queue, bins: &[f32]) {
+        let mut bins = bins.to_vec();
+        bins.resize(self.bin_count, 0.0);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&bins));
+    }
+}
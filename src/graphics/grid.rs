@@ -0,0 +1,172 @@
+use wgpu::{BindGroup, Buffer, RenderPipeline};
+use wgpu::util::DeviceExt;
+use crate::graphics::model::Vertex;
+use crate::graphics::renderer::RenderBatch;
+use crate::graphics::{State, texture};
+
+const GRID_SHADER: &str = include_str!("grid.wgsl");
+
+pub const GRID_MODULE_NAME: &str = "Debug Grid";
+
+// Number of grid cells from the origin to the edge, and the size of each cell
+const GRID_EXTENT: i32 = 10;
+const GRID_SPACING: f32 = 1.0;
+const GRID_COLOR: [f32; 3] = [0.35, 0.35, 0.35];
+const X_AXIS_COLOR: [f32; 3] = [0.8, 0.2, 0.2];
+const Z_AXIS_COLOR: [f32; 3] = [0.2, 0.2, 0.8];
+
+// Lines on the XZ plane through the origin with the X and Z axes highlighted, so the position
+// and scale of the avatar modules can be judged. Hidden until it's toggled on
+pub struct GridBatch {
+    visible: bool,
+    render_pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    indices: Vec<u32>,
+}
+
+impl GridBatch {
+    pub fn new(state: &State) -> Self {
+        let shader = state.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Grid Shader"),
+            source: wgpu::ShaderSource::Wgsl(GRID_SHADER.into()),
+        });
+
+        let render_pipeline_layout = state.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Grid Pipeline Layout"),
+            bind_group_layouts: &[&state.default_bind_group.default_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let render_pipeline = state.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Grid Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: state.config.format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: state.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
+
+        let (vertices, indices) = grid_lines();
+
+        let vertex_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Grid Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vertices[..]),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = state.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Grid Index Buffer"),
+            contents: bytemuck::cast_slice(&indices[..]),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Self {
+            visible: false,
+            render_pipeline,
+            vertex_buffer,
+            index_buffer,
+            indices,
+        }
+    }
+}
+
+// One line along X and one along Z for every step across the grid, as a line list
+fn grid_lines() -> (Vec<Vertex>, Vec<u32>) {
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let edge = GRID_EXTENT as f32 * GRID_SPACING;
+    let mut add_line = |start: [f32; 3], end: [f32; 3], color: [f32; 3]| {
+        for position in [start, end] {
+            vertices.push(Vertex {position, color, index: 0.0, normal: [0.0, 1.0, 0.0]});
+        }
+    };
+
+    for step in -GRID_EXTENT..=GRID_EXTENT {
+        let offset = step as f32 * GRID_SPACING;
+        // The line along X through the origin is the X axis, and the one along Z is the Z axis
+        let (x_color, z_color) = if step == 0 { (X_AXIS_COLOR, Z_AXIS_COLOR) } else { (GRID_COLOR, GRID_COLOR) };
+        add_line([-edge, 0.0, offset], [edge, 0.0, offset], x_color);
+        add_line([offset, 0.0, -edge], [offset, 0.0, edge], z_color);
+    }
+
+    let indices = (0..vertices.len() as u32).collect();
+    (vertices, indices)
+}
+
+impl RenderBatch for GridBatch {
+    fn get_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.render_pipeline)
+    }
+
+    // The default bind group has the camera
+    fn get_bind_group(&self) -> Option<&BindGroup> {
+        None
+    }
+
+    fn get_vertex_buffer(&self) -> &Buffer {
+        &self.vertex_buffer
+    }
+
+    fn get_index_buffer(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    // The grid lines never change, so their vertices are only kept in the vertex buffer
+    fn get_vertices(&self) -> &[Vertex] {
+        &[]
+    }
+
+    fn get_indices(&self) -> &[u32] {
+        &self.indices[..]
+    }
+
+    fn get_indices_count(&self) -> u32 {
+        self.indices.len() as u32
+    }
+
+    fn get_instance_buffer(&self) -> Option<&Buffer> {
+        None
+    }
+
+    fn get_instance_count(&self) -> Option<u16> {
+        None
+    }
+
+    fn get_visible(&self) -> bool {
+        self.visible
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    fn get_module_name(&self) -> &str {
+        GRID_MODULE_NAME
+    }
+}
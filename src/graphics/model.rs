@@ -8,6 +8,16 @@ pub struct Vertex {
     pub(crate) position: [f32; 3],
     pub(crate) color: [f32; 3],
     pub(crate) index: f32,
+    pub(crate) normal: [f32; 3],
+}
+
+// Normal for meshes without faces, pointing away from the origin
+pub(crate) fn position_normal(position: [f32; 3]) -> [f32; 3] {
+    let length = (position[0] * position[0] + position[1] * position[1] + position[2] * position[2]).sqrt();
+    if length == 0.0 {
+        return [0.0, 1.0, 0.0];
+    }
+    [position[0] / length, position[1] / length, position[2] / length]
 }
 
 
@@ -16,6 +26,9 @@ pub struct Vertex {
 pub(crate) struct Instance {
     pub(crate) position: cgmath::Vector3<f32>,
     pub(crate) rotation: cgmath::Quaternion<f32>,
+    pub(crate) scale: f32,
+    // Multiplied with the vertex colors in the shader
+    pub(crate) color: [f32; 3],
 }
 
 // TODO: move this method to a more appropriate place
@@ -23,12 +36,16 @@ pub(crate) struct Instance {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct InstanceRaw {
     model: [[f32;4]; 4],
+    color: [f32; 3],
 }
 
 impl Instance {
     pub(crate) fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
+            model: (cgmath::Matrix4::from_translation(self.position)
+                * cgmath::Matrix4::from(self.rotation)
+                * cgmath::Matrix4::from_scale(self.scale)).into(),
+            color: self.color,
         }
     }
 }
@@ -68,6 +85,11 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
@@ -92,74 +114,189 @@ impl Vertex {
                     format: wgpu::VertexFormat::Float32x3,
                 },
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<f32>() as wgpu::BufferAddress,
+                    offset: mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
-    pub indices: Vec<u16>,
+    pub indices: Vec<u32>,
 }
 
 impl Mesh {
 
-    pub(crate) fn new(vertices: Vec<Vertex>, indices: Vec<u16>) -> Self {
+    pub(crate) fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
         Self {
             vertices,
             indices,
         }
     }
 
-    pub fn new_empty() -> Self{
-        let vertices: Vec<Vertex> = Vec::new();
-        let indices: Vec<u16> = Vec::new();
+    // Smooth normals for a triangle list, averaging the normals of every face touching a vertex
+    pub(crate) fn compute_normals(&mut self) {
+        let mut normals = vec![cgmath::Vector3::new(0.0f32, 0.0, 0.0); self.vertices.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let a = cgmath::Vector3::from(self.vertices[triangle[0] as usize].position);
+            let b = cgmath::Vector3::from(self.vertices[triangle[1] as usize].position);
+            let c = cgmath::Vector3::from(self.vertices[triangle[2] as usize].position);
+            let face_normal = (b - a).cross(c - a);
+            for &index in triangle {
+                normals[index as usize] += face_normal;
+            }
+        }
 
-        Self {
-            vertices,
-            indices,
+        for (vertex, normal) in self.vertices.iter_mut().zip(normals) {
+            vertex.normal = position_normal(normal.into());
         }
     }
 }
 
 pub mod mesh_generation {
-    use std::fs::File;
-    use std::io::BufReader;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
     use obj::{load_obj, Obj};
-    use crate::graphics::model::{Mesh, Vertex};
-
-    pub fn load_mesh_from_file(file_path: String) -> Mesh {
-        let input = BufReader::new(File::open(file_path).unwrap());
-        let dome: Obj = load_obj(input).unwrap();
+    use crate::graphics::model::{Mesh, position_normal, Vertex};
+
+    // The returned bool is true when the file carried its own vertex colors. Files without normals
+    // get smooth normals computed from their faces
+    pub fn load_mesh_from_file(file_path: String) -> Result<(Mesh, bool), String> {
+        let source = fs::read_to_string(crate::asset_path(&file_path))
+            .map_err(|e| format!("could not read {}: {}", file_path, e))?;
+        let (source, colors) = split_obj_vertex_colors(&source);
+
+        // obj-rs can only load vertices with normals from files that have them
+        let has_normals = source.lines().any(|line| line.starts_with("vn "));
+        let (obj_vertices, obj_indices): (Vec<([f32; 3], [f32; 3])>, Vec<u32>) = if has_normals {
+            let obj: Obj<obj::Vertex, u32> = load_obj(source.as_bytes())
+                .map_err(|e| format!("could not load {}: {}", file_path, e))?;
+            (obj.vertices.iter().map(|vertex| (vertex.position, vertex.normal)).collect(), obj.indices)
+        } else {
+            let obj: Obj<obj::Position, u32> = load_obj(source.as_bytes())
+                .map_err(|e| format!("could not load {}: {}", file_path, e))?;
+            (obj.vertices.iter().map(|vertex| (vertex.position, [0.0; 3])).collect(), obj.indices)
+        };
 
         let mut vertices: Vec<Vertex> = Vec::new();
-        let mut indices: Vec<u16> = Vec::new();
 
-        let vertice_count = vertices.len();
+        let vertice_count = obj_vertices.len();
+        if vertice_count == 0 {
+            return Err(format!("{} has no vertices", file_path));
+        }
 
-        for (index, vertice) in dome.vertices.into_iter().enumerate() {
-            let r = vertice.position[0] * (index as f32 / vertice_count as f32);
-            let g = vertice.position[1] * (index as f32 / vertice_count as f32);
-            let b = vertice.position[2] * (index as f32 / vertice_count as f32);
+        for (index, (position, normal)) in obj_vertices.into_iter().enumerate() {
+            // obj-rs merges vertices, so the color is found again by position
+            let color = match colors.get(&position_key(position)) {
+                Some(color) => *color,
+                None => {
+                    let r = position[0] * (index as f32 / vertice_count as f32);
+                    let g = position[1] * (index as f32 / vertice_count as f32);
+                    let b = position[2] * (index as f32 / vertice_count as f32);
+                    [r,g,b]
+                }
+            };
             vertices.push(Vertex {
-                position: vertice.position,
-                color: [r,g,b],
+                position,
+                color,
                 index: index as f32/ vertice_count as f32,
+                normal,
             });
         }
 
-        for index in dome.indices {
-            indices.push(index);
+        let mut mesh = Mesh {
+            vertices,
+            indices: obj_indices,
+        };
+        if !has_normals {
+            mesh.compute_normals();
         }
+        Ok((mesh, !colors.is_empty()))
+    }
 
-        Mesh {
-            vertices,
-            indices,
+    fn position_key(position: [f32; 3]) -> [u32; 3] {
+        [position[0].to_bits(), position[1].to_bits(), position[2].to_bits()]
+    }
+
+    // Vertex colors are written as "v x y z r g b", which obj-rs can't parse. They are taken out
+    // of the source here and returned by position so they can be put back on the loaded vertices
+    fn split_obj_vertex_colors(source: &str) -> (String, HashMap<[u32; 3], [f32; 3]>) {
+        let mut stripped = String::with_capacity(source.len());
+        let mut colors = HashMap::new();
+
+        for line in source.lines() {
+            let values: Vec<f32> = match line.strip_prefix("v ") {
+                Some(rest) => rest.split_whitespace().filter_map(|value| value.parse().ok()).collect(),
+                None => Vec::new(),
+            };
+            if values.len() == 6 {
+                colors.insert(position_key([values[0], values[1], values[2]]), [values[3], values[4], values[5]]);
+                stripped.push_str(&format!("v {} {} {}\n", values[0], values[1], values[2]));
+            } else {
+                stripped.push_str(line);
+                stripped.push('\n');
+            }
+        }
+
+        (stripped, colors)
+    }
+
+    // Loads every primitive of every mesh in a glTF or GLB file into one mesh. Node transforms
+    // are ignored, so each primitive keeps the position it was modelled at
+    pub fn load_mesh_from_gltf(file_path: String) -> Result<(Mesh, bool), String> {
+        let (document, buffers, _) = gltf::import(crate::asset_path(&file_path))
+            .map_err(|e| format!("could not load {}: {}", file_path, e))?;
+
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut has_colors = false;
+
+        for primitive in document.meshes().flat_map(|mesh| mesh.primitives()) {
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: Vec<[f32; 3]> = match reader.read_positions() {
+                Some(t) => t.collect(),
+                None => continue,
+            };
+            let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|normals| normals.collect());
+            let colors: Option<Vec<[f32; 3]>> = reader.read_colors(0).map(|colors| colors.into_rgb_f32().collect());
+            has_colors |= colors.is_some();
+
+            let first = vertices.len() as u32;
+            for (index, position) in positions.iter().enumerate() {
+                vertices.push(Vertex {
+                    position: *position,
+                    color: colors.as_ref().map(|colors| colors[index]).unwrap_or([1.0, 1.0, 1.0]),
+                    index: 0.0,
+                    normal: normals.as_ref().map(|normals| normals[index]).unwrap_or(position_normal(*position)),
+                });
+            }
+
+            // Primitives without indices draw their vertices in order
+            match reader.read_indices() {
+                Some(t) => indices.extend(t.into_u32().map(|index| first + index)),
+                None => indices.extend(first..vertices.len() as u32),
+            }
+        }
+
+        // Same as OBJ meshes, the index is how far through the mesh the vertex is
+        let vertice_count = vertices.len();
+        if vertice_count == 0 {
+            return Err(format!("{} has no vertices", file_path));
         }
+        for (index, vertex) in vertices.iter_mut().enumerate() {
+            vertex.index = index as f32 / vertice_count as f32;
+        }
+
+        Ok((Mesh::new(vertices, indices), has_colors))
     }
 
     pub fn gen_outer_mesh() -> Mesh {
@@ -168,7 +305,7 @@ pub mod mesh_generation {
         let points = fibonacci_sphere_points(samples);
 
         let mut vertices: Vec<Vertex> = Vec::new();
-        let mut indices: Vec<u16> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
 
         for (index, (x, y , z)) in points.into_iter().enumerate() {
             let r:f32 = (x + 1.0)/2.0;
@@ -177,10 +314,11 @@ pub mod mesh_generation {
 
             vertices.push(Vertex {position: [x*1.5, y*1.5, z*1.5],
                 color:[r,g,b],
-                index: if index % 11 == 0 {1.0} else {0.0}});
+                index: if index % 11 == 0 {1.0} else {0.0},
+                normal: position_normal([x, y, z])});
 
             indices.push(0);
-            indices.push(index as u16);
+            indices.push(index as u32);
 
         }
 
@@ -194,10 +332,10 @@ pub mod mesh_generation {
         let points = fibonacci_sphere_points(samples);
 
         let mut vertices: Vec<Vertex> = Vec::new();
-        let mut indices: Vec<u16> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
 
         // Add the center vertices
-        vertices.push(Vertex {position:[0.0,0.0,0.0], color:[0.0,0.0,0.0], index:0f32});
+        vertices.push(Vertex {position:[0.0,0.0,0.0], color:[0.0,0.0,0.0], index:0f32, normal:[0.0,1.0,0.0]});
 
         for (index, (x, y , z)) in points.into_iter().enumerate() {
             let r:f32 = (x + 1.0)/2.0;
@@ -206,10 +344,11 @@ pub mod mesh_generation {
 
             vertices.push(Vertex {position: [x, y, z],
                 color:[r,g,b],
-                index: if index % 11 == 0 {1.0} else {0.0}});
+                index: if index % 11 == 0 {1.0} else {0.0},
+                normal: position_normal([x, y, z])});
 
             indices.push(0);
-            indices.push(index as u16);
+            indices.push(index as u32);
 
         }
 
@@ -255,7 +394,7 @@ pub mod mesh_generation {
         ];
 
         let mut vertices: Vec<Vertex> = Vec::new();
-        let mut indices: Vec<u16> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
 
         for index in indice_list {
             indices.push(index);
@@ -266,6 +405,9 @@ pub mod mesh_generation {
                 position: [x as f32, y as f32, z as f32],
                 color: [(x as f32 + 1.0) / 2.0, (y as f32 + 1.0) / 2.0, (z as f32 + 1.0) / 2.0],
                 index: index as f32 / vertice_position_list.len() as f32,
+                // The faces listed above don't share a winding order, but the average of the three
+                // faces touching each corner points straight out from the center
+                normal: position_normal([x as f32, y as f32, z as f32]),
             })
         }
 
@@ -276,36 +418,86 @@ pub mod mesh_generation {
 
     }
 
+    // Closed sphere made out of rings of vertices from the top pole to the bottom pole
+    pub fn gen_uv_sphere_mesh(rings: usize, sectors: usize) -> Mesh {
+        let rings = rings.max(2);
+        let sectors = sectors.max(3);
+
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        for ring in 0..=rings {
+            let phi = std::f32::consts::PI * ring as f32 / rings as f32;
+            for sector in 0..=sectors {
+                let theta = 2.0 * std::f32::consts::PI * sector as f32 / sectors as f32;
+
+                let x = phi.sin() * theta.cos();
+                let y = phi.cos();
+                let z = phi.sin() * theta.sin();
+
+                vertices.push(Vertex {
+                    position: [x, y, z],
+                    color: [(x + 1.0) / 2.0, (y + 1.0) / 2.0, (z + 1.0) / 2.0],
+                    index: ring as f32 / rings as f32,
+                    // Every point on a unit sphere is its own normal
+                    normal: [x, y, z],
+                });
+            }
+        }
+
+        // Two counter clockwise triangles per quad, skipping the degenerate ones at the poles
+        for ring in 0..rings {
+            for sector in 0..sectors {
+                let current = (ring * (sectors + 1) + sector) as u32;
+                let below = current + sectors as u32 + 1;
+
+                if ring != 0 {
+                    indices.extend_from_slice(&[current, current + 1, below]);
+                }
+                if ring != rings - 1 {
+                    indices.extend_from_slice(&[current + 1, below + 1, below]);
+                }
+            }
+        }
+
+        Mesh::new(vertices, indices)
+    }
+
     fn gen_triangle_mesh() -> Mesh {
 
         let mut vertices: Vec<Vertex> = Vec::new();
-        let mut indices: Vec<u16> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
         let size: f32 = 0.03;
 
         vertices.push(Vertex {
             position: [size, size, size],
             color: [1.0,0.0,0.0],
-            index: 0.9
+            index: 0.9,
+            normal: [0.0, 0.0, 0.0],
         });
         vertices.push(Vertex {
             position: [size, 0.00, size],
             color: [0.0,1.0,0.0],
-            index: 0.6
+            index: 0.6,
+            normal: [0.0, 0.0, 0.0],
         });
         vertices.push(Vertex {
             position: [0.00, size, size],
             color: [0.0,0.0,1.0],
-            index: 0.3
+            index: 0.3,
+            normal: [0.0, 0.0, 0.0],
         });
         vertices.push(Vertex {
             position: [size, size, 0.0],
             color: [0.0,0.0,1.0],
-            index: 0.3
+            index: 0.3,
+            normal: [0.0, 0.0, 0.0],
         });
         vertices.push(Vertex {
             position: [size, 0.0, 0.0],
             color: [0.0,0.0,1.0],
-            index: 0.3
+            index: 0.3,
+            normal: [0.0, 0.0, 0.0],
         });
 
         indices.push(0);
@@ -318,10 +510,175 @@ pub mod mesh_generation {
         indices.push(2);
         indices.push(3);
 
-        Mesh {
+        let mut mesh = Mesh {
             vertices,
             indices,
+        };
+        mesh.compute_normals();
+        mesh
+    }
+
+    // Cylinder standing on the Y axis, centered on the origin. A top radius of 0 makes a cone.
+    // The index goes from 0 at the bottom to 1 at the top
+    pub fn gen_cylinder_mesh(radius: f32, top_radius: f32, height: f32, segments: usize, capped: bool) -> Mesh {
+        let segments = segments.max(3);
+        let half_height = height / 2.0;
+
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        let rim = |segment: usize| {
+            let theta = 2.0 * std::f32::consts::PI * segment as f32 / segments as f32;
+            (theta.cos(), theta.sin())
+        };
+        let color = |position: [f32; 3]| {
+            [(position[0] + 1.0) / 2.0, (position[1] + 1.0) / 2.0, (position[2] + 1.0) / 2.0]
+        };
+
+        // Side, the first and last segment get their own vertices so the seam is a clean edge
+        for segment in 0..=segments {
+            let (cos, sin) = rim(segment);
+            // Leans the normal up or down by the slope of the side
+            let normal = position_normal([cos * height, radius - top_radius, sin * height]);
+            for (y, ring_radius, index) in [(-half_height, radius, 0.0), (half_height, top_radius, 1.0)] {
+                let position = [cos * ring_radius, y, sin * ring_radius];
+                vertices.push(Vertex {position, color: color(position), index, normal});
+            }
+        }
+        for segment in 0..segments as u32 {
+            let bottom = segment * 2;
+            let top = bottom + 1;
+            indices.extend_from_slice(&[bottom, top, bottom + 2, bottom + 2, top, top + 2]);
         }
+
+        // Caps are fans around their own center vertex, with normals straight up and down
+        if capped {
+            for (y, cap_radius, index, normal) in [(-half_height, radius, 0.0, -1.0), (half_height, top_radius, 1.0, 1.0)] {
+                let center = vertices.len() as u32;
+                let position = [0.0, y, 0.0];
+                vertices.push(Vertex {position, color: color(position), index, normal: [0.0, normal, 0.0]});
+                for segment in 0..=segments {
+                    let (cos, sin) = rim(segment);
+                    let position = [cos * cap_radius, y, sin * cap_radius];
+                    vertices.push(Vertex {position, color: color(position), index, normal: [0.0, normal, 0.0]});
+                }
+                // Counter clockwise seen from outside of the cylinder
+                for segment in 1..=segments as u32 {
+                    if normal > 0.0 {
+                        indices.extend_from_slice(&[center, center + segment + 1, center + segment]);
+                    } else {
+                        indices.extend_from_slice(&[center, center + segment, center + segment + 1]);
+                    }
+                }
+            }
+        }
+
+        Mesh::new(vertices, indices)
+    }
+
+    // Regular polygon in the XY plane facing +Z, a center vertex with a fan of triangles to the
+    // rim. Line lists can't draw the fan, so with `outline` the indices are the rim's edges and
+    // spokes from the center instead. The index goes around the rim from 0 to 1
+    pub fn gen_polygon_mesh(sides: usize, radius: f32, outline: bool) -> Mesh {
+        let sides = sides.max(3);
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        vertices.push(Vertex {position: [0.0, 0.0, 0.0], color: [1.0, 1.0, 1.0], index: 0.0, normal: [0.0, 0.0, 1.0]});
+        for side in 0..sides {
+            let theta = 2.0 * std::f32::consts::PI * side as f32 / sides as f32;
+            let (x, y) = (theta.cos(), theta.sin());
+            vertices.push(Vertex {
+                position: [x * radius, y * radius, 0.0],
+                color: [(x + 1.0) / 2.0, (y + 1.0) / 2.0, 0.5],
+                index: side as f32 / sides as f32,
+                normal: [0.0, 0.0, 1.0],
+            });
+        }
+
+        for side in 1..=sides as u32 {
+            let next = side % sides as u32 + 1;
+            if outline {
+                indices.extend_from_slice(&[0, side, side, next]);
+            } else {
+                indices.extend_from_slice(&[0, side, next]);
+            }
+        }
+
+        Mesh::new(vertices, indices)
+    }
+
+    // Line list indices of every edge of a triangle list, edges shared by two triangles are only
+    // drawn once
+    pub fn wireframe_indices(indices: &[u32]) -> Vec<u32> {
+        let mut edges = HashSet::new();
+        let mut line_indices = Vec::new();
+        for triangle in indices.chunks_exact(3) {
+            for (a, b) in [(triangle[0], triangle[1]), (triangle[1], triangle[2]), (triangle[2], triangle[0])] {
+                if edges.insert((a.min(b), a.max(b))) {
+                    line_indices.extend_from_slice(&[a, b]);
+                }
+            }
+        }
+        line_indices
+    }
+
+    // A flat line of points from x = -1 to 1, connected as a line list. The height of each
+    // point is set to an audio sample every frame
+    pub fn gen_waveform_mesh(samples: usize) -> Mesh {
+        let samples = samples.max(2);
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        for i in 0..samples {
+            let x = i as f32 / (samples - 1) as f32 * 2.0 - 1.0;
+            vertices.push(Vertex {position: [x, 0.0, 0.0], color: [1.0, 1.0, 1.0], index: 0.0, normal: [0.0, 0.0, 1.0]});
+            if i > 0 {
+                indices.push(i as u32 - 1);
+                indices.push(i as u32);
+            }
+        }
+
+        Mesh::new(vertices, indices)
+    }
+
+    // Replaces every vertex with 4 copies of itself and two triangles between them, so that
+    // a shader can spread the copies out into a quad around the original point
+    pub fn expand_points_to_quads(mesh: &Mesh) -> Mesh {
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        for vertex in mesh.vertices.iter() {
+            let first = vertices.len() as u32;
+            for _ in 0..4 {
+                vertices.push(*vertex);
+            }
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        Mesh::new(vertices, indices)
+    }
+
+    // Replaces every line segment with a quad made out of 2 copies of each end. The normal of
+    // each copy is set to the position of the other end of the segment, which the thick line
+    // shader uses to find the direction to widen the line in
+    pub fn expand_lines_to_quads(mesh: &Mesh) -> Mesh {
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        for segment in mesh.indices.chunks_exact(2) {
+            let start = mesh.vertices[segment[0] as usize];
+            let end = mesh.vertices[segment[1] as usize];
+
+            let first = vertices.len() as u32;
+            vertices.push(Vertex {normal: end.position, ..start});
+            vertices.push(Vertex {normal: end.position, ..start});
+            vertices.push(Vertex {normal: start.position, ..end});
+            vertices.push(Vertex {normal: start.position, ..end});
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        Mesh::new(vertices, indices)
     }
 
     pub fn fibonacci_sphere_points(samples: u32) -> Vec<(f32, f32, f32)> {
@@ -344,4 +701,77 @@ pub mod mesh_generation {
         return points;
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+    use crate::graphics::model::mesh_generation::{gen_cylinder_mesh, load_mesh_from_file};
+
+    #[test]
+    fn test_load_mesh_from_file_colors_are_finite() {
+        let path = std::env::temp_dir().join("sound_guy_test_triangle.obj");
+        fs::write(&path, "v 0.0 0.0 0.0\nv 1.0 0.0 0.0\nv 0.0 1.0 0.0\nf 1 2 3\n").unwrap();
+
+        let (mesh, _) = load_mesh_from_file(path.to_str().unwrap().to_string()).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(mesh.vertices.len(), 3);
+        for vertex in mesh.vertices {
+            assert!(vertex.color.iter().all(|c| c.is_finite()));
+            assert!(vertex.index.is_finite());
+            // The file has no normals, so they come from the face
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn test_load_mesh_from_empty_file() {
+        let path = std::env::temp_dir().join("sound_guy_test_empty.obj");
+        fs::write(&path, "").unwrap();
+
+        let result = load_mesh_from_file(path.to_str().unwrap().to_string());
+        fs::remove_file(&path).ok();
+
+        match result {
+            Err(e) => assert!(e.contains("has no vertices")),
+            Ok(_) => panic!("Expected an empty file to be an error"),
+        }
+    }
+
+    #[test]
+    fn test_load_mesh_from_file_keeps_vertex_colors() {
+        let path = std::env::temp_dir().join("sound_guy_test_colored_triangle.obj");
+        fs::write(&path, "v 0.0 0.0 0.0 1.0 0.0 0.0\nv 1.0 0.0 0.0 0.0 1.0 0.0\nv 0.0 1.0 0.0 0.0 0.0 1.0\nf 1 2 3\n").unwrap();
+
+        let (mesh, has_colors) = load_mesh_from_file(path.to_str().unwrap().to_string()).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert!(has_colors);
+        for vertex in mesh.vertices {
+            // Each vertex was colored after the axis it lies on
+            let expected = if vertex.position[0] > 0.5 {
+                [0.0, 1.0, 0.0]
+            } else if vertex.position[1] > 0.5 {
+                [0.0, 0.0, 1.0]
+            } else {
+                [1.0, 0.0, 0.0]
+            };
+            assert_eq!(vertex.color, expected);
+        }
+    }
+
+    #[test]
+    fn test_capped_cylinder_triangles() {
+        let segments = 8;
+        let mesh = gen_cylinder_mesh(1.0, 0.5, 2.0, segments, true);
+
+        // Two triangles per side segment and one per segment on each cap
+        assert_eq!(mesh.indices.len(), segments * 4 * 3);
+        assert!(mesh.indices.iter().all(|index| (*index as usize) < mesh.vertices.len()));
+        for vertex in mesh.vertices {
+            assert!(vertex.position[1] == -1.0 || vertex.position[1] == 1.0);
+            assert!(vertex.index == 0.0 || vertex.index == 1.0);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::*;
+
+// Saves the rendered frames as a numbered PNG sequence with a fixed frame rate. Frames are
+// repeated or dropped by how long they were on screen, so the sequence plays back at the same
+// speed it was rendered at no matter how fast the frames were drawn
+pub struct Recorder {
+    directory: PathBuf,
+    duration: Duration,
+    frame_time: Duration,
+    // Time since the first frame, and the time of the next image to write
+    elapsed: Duration,
+    next_image_time: Duration,
+    image_count: usize,
+}
+
+impl Recorder {
+    pub fn new(directory: &Path, duration: Duration, fps: u32) -> Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            duration,
+            frame_time: Duration::from_secs_f64(1.0 / fps.max(1) as f64),
+            elapsed: Duration::ZERO,
+            next_image_time: Duration::ZERO,
+            image_count: 0,
+        })
+    }
+
+    // Adds a frame that was drawn dt after the previous one, as tightly packed RGBA pixels.
+    // Returns true once the whole duration has been recorded
+    pub fn add_frame(&mut self, pixels: &[u8], width: u32, height: u32, dt: Duration) -> Result<bool> {
+        // The first frame starts the recording, however long it took to get to it
+        if self.image_count > 0 {
+            self.elapsed += dt;
+        }
+
+        while self.next_image_time <= self.elapsed && self.next_image_time < self.duration {
+            let path = self.directory.join(format!("frame_{:05}.png", self.image_count));
+            image::save_buffer(&path, pixels, width, height, image::ColorType::Rgba8)?;
+            self.image_count += 1;
+            self.next_image_time += self.frame_time;
+        }
+
+        Ok(self.next_image_time >= self.duration)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn image_count(&self) -> usize {
+        self.image_count
+    }
+}
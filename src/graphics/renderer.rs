@@ -1,43 +1,135 @@
-use wgpu::{Buffer, RenderPipeline};
-use crate::graphics::avatar::AvatarModule;
+use std::path::PathBuf;
+use wgpu::{BindGroup, Buffer, RenderPipeline};
 use crate::graphics::model::{Vertex};
+use crate::graphics::recorder::Recorder;
+use crate::graphics::screenshot::CapturedFrame;
 use crate::graphics::State;
 
 pub(crate) struct Renderer {
     render_batches: Vec<Box<dyn RenderBatch>>,
+    // Where to save a screenshot of the next frame, if one was requested
+    screenshot_path: Option<PathBuf>,
+    // Every frame is copied out to the recorder while it's set
+    recorder: Option<Recorder>,
 }
 
 pub(crate) trait RenderBatch {
     fn get_pipeline(&self) -> Option<&RenderPipeline>;
+    // Bind group 0 for the batch, the default bind group is used when None
+    fn get_bind_group(&self) -> Option<&BindGroup>;
     fn get_vertex_buffer(&self) -> &Buffer;
     fn get_index_buffer(&self) -> &Buffer;
     fn get_vertices(&self) -> &[Vertex];
-    fn get_indices(&self) -> &[u16];
+    fn get_indices(&self) -> &[u32];
     fn get_indices_count(&self) -> u32;
     fn get_instance_buffer(&self) -> Option<&Buffer>;
     fn get_instance_count(&self) -> Option<u16>;
     fn get_visible(&self) -> bool;
-}
+    fn set_visible(&mut self, visible: bool);
+    fn get_module_name(&self) -> &str;
+
+    // Bind group 3 for batches whose shader declares its own uniforms
+    fn get_custom_bind_group(&self) -> Option<&BindGroup> {
+        None
+    }
+
+    // Writes a new value for one of the batch's own uniforms, false when it has none with the name
+    fn set_custom_uniform(&self, _state: &State, _name: &str, _value: &[f32]) -> bool {
+        false
+    }
 
-const BACKGROUND_COLOR: [f64; 4] = [0.0,0.0,0.0,0.0];
+    // Called after the window and surface have been resized. Override this for batches whose
+    // buffers depend on the size or aspect ratio of the screen, such as aspect corrected layouts
+    fn on_resize(&mut self, _state: &State) {}
+
+    // Called every frame before rendering, for batches whose buffers change over time
+    fn update(&mut self, _state: &State) {}
+
+    // Called when the shaders should be read from disk again. The buffers are kept as they are,
+    // only the pipeline needs to be rebuilt
+    fn reload_shader(&mut self, _state: &State) {}
+}
 
-// TODO: need to update render batches when the screen gets resized
 impl Renderer {
     pub fn new() -> Self {
         let render_batches = Vec::new();
         Self {
-            render_batches
+            render_batches,
+            screenshot_path: None,
+            recorder: None,
+        }
+    }
+
+    // Lets every render batch update itself for the new screen size
+    pub fn resize(&mut self, state: &State) {
+        for render_batch in self.render_batches.iter_mut() {
+            render_batch.on_resize(state);
+        }
+    }
+
+    pub fn update(&mut self, state: &State) {
+        for render_batch in self.render_batches.iter_mut() {
+            render_batch.update(state);
+        }
+    }
+
+    pub fn reload_shaders(&mut self, state: &State) {
+        for render_batch in self.render_batches.iter_mut() {
+            render_batch.reload_shader(state);
         }
     }
 
-    pub fn add_render_batch(&mut self, render_batch: Box<AvatarModule>) {
+    // Removes every render batch with the module name
+    pub fn remove_render_batch(&mut self, module_name: &str) {
+        self.render_batches.retain(|render_batch| render_batch.get_module_name() != module_name);
+    }
+
+    pub fn set_visible(&mut self, module_name: &str, visible: bool) {
+        for render_batch in self.render_batches.iter_mut() {
+            if render_batch.get_module_name() == module_name {
+                render_batch.set_visible(visible);
+            }
+        }
+    }
+
+    // Visibility of the first render batch with the module name
+    pub fn get_visible(&self, module_name: &str) -> Option<bool> {
+        self.render_batches.iter()
+            .find(|render_batch| render_batch.get_module_name() == module_name)
+            .map(|render_batch| render_batch.get_visible())
+    }
+
+    // Sets a custom uniform of the first render batch with the module name that has it
+    pub fn set_custom_uniform(&self, state: &State, module_name: &str, uniform_name: &str, value: &[f32]) -> bool {
+        self.render_batches.iter()
+            .filter(|render_batch| render_batch.get_module_name() == module_name)
+            .any(|render_batch| render_batch.set_custom_uniform(state, uniform_name, value))
+    }
+
+    pub fn request_screenshot(&mut self, path: PathBuf) {
+        self.screenshot_path = Some(path);
+    }
+
+    pub fn start_recording(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn add_render_batch(&mut self, render_batch: Box<dyn RenderBatch>) {
         self.render_batches.push(render_batch);
     }
 
+    // Renders the next frame to the surface, the usual path when there's a window
     pub fn render(&mut self, state: &State) -> Result<(), wgpu::SurfaceError> {
         let device = &state.device;
-        let surface = &state.surface;
         let queue = &state.queue;
+        let surface = match &state.surface {
+            Some(t) => t,
+            None => return Ok(()),
+        };
 
         let output = surface.get_current_texture().unwrap();
         let view = output
@@ -47,65 +139,166 @@ impl Renderer {
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
         });
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: BACKGROUND_COLOR[0],
-                            g: BACKGROUND_COLOR[1],
-                            b: BACKGROUND_COLOR[2],
-                            a: BACKGROUND_COLOR[3],
-                        }),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &state.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+        self.render_to(state, &mut encoder, &view, &state.depth_texture.view);
+
+        // The surface can't be copied from on every backend, so frames that are saved are drawn
+        // a second time into a texture that can
+        let capture_texture = if self.screenshot_path.is_some() || self.recorder.is_some() {
+            let texture = create_capture_texture(state);
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            self.render_to(state, &mut encoder, &view, &state.depth_texture.view);
+            Some(texture)
+        } else {
+            None
+        };
+        let screenshot = self.screenshot_path.take().zip(capture_texture.as_ref()).map(|(path, texture)| {
+            (path, CapturedFrame::capture(device, &mut encoder, texture, state.config.width, state.config.height))
+        });
+        let recorded_frame = self.recorder.as_ref().zip(capture_texture.as_ref()).map(|(_, texture)| {
+            CapturedFrame::capture(device, &mut encoder, texture, state.config.width, state.config.height)
+        });
 
-            // Draw all of the render batches in the renderer
-            for render_batch in self.render_batches.iter() {
-                // Skip the rendering if the current render batch is not visible
-                if !render_batch.get_visible() {
-                    continue;
-                }
-                let pipeline = render_batch.get_pipeline().unwrap();
-                let vertex_buffer = render_batch.get_vertex_buffer();
-                let index_buffer = render_batch.get_index_buffer();
-
-                // Pass in all of the bind groups
-                render_pass.set_pipeline(pipeline);
-                render_pass.set_bind_group(0, &state.default_bind_group.default_bindings, &[]);
-                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-                match render_batch.get_instance_buffer() {
-                    None => {}
-                    Some(buffer) => {
-                        render_pass.set_vertex_buffer(1, buffer.slice(..));
-                    }
-                }
-                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-                render_pass.draw_indexed(0..render_batch.get_indices_count(), 0, 0..match render_batch.get_instance_count() {
-                    None => {1}
-                    Some(t) => {
-                        t as u32}
-                });
-            }
-        }
         // Output to the screen
         queue.submit(std::iter::once(encoder.finish()));
+
+        if let Some((path, frame)) = screenshot {
+            match frame.save_png(device, state.config.format, state.transparent_background, &path) {
+                Ok(_) => println!("Saved screenshot to {}", path.display()),
+                Err(e) => log::error!("Could not save screenshot to {}: {}", path.display(), e),
+            }
+        }
+
+        if let Some(frame) = recorded_frame {
+            self.record_frame(state, frame);
+        }
+
         output.present();
 
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Hands the frame to the recorder, and stops recording once it has everything or fails
+    fn record_frame(&mut self, state: &State, frame: CapturedFrame) {
+        let recorder = match self.recorder.as_mut() {
+            Some(t) => t,
+            None => return,
+        };
+
+        let finished = frame.read_rgba(&state.device, state.config.format, state.transparent_background)
+            .and_then(|pixels| recorder.add_frame(&pixels, state.config.width, state.config.height, state.dt));
+        match finished {
+            Ok(false) => {}
+            Ok(true) => {
+                println!("Saved {} frames to {}", recorder.image_count(), recorder.directory().display());
+                self.recorder = None;
+            }
+            Err(e) => {
+                log::error!("Could not record frame to {}: {}", recorder.directory().display(), e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Renders a frame into a new texture the size and format of the surface config instead of
+    // the surface, and copies it out so it can be saved
+    pub fn render_offscreen(&mut self, state: &State) -> CapturedFrame {
+        let device = &state.device;
+
+        let texture = create_capture_texture(state);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Offscreen Render Encoder"),
+        });
+        self.render_to(state, &mut encoder, &view, &state.depth_texture.view);
+        let frame = CapturedFrame::capture(device, &mut encoder, &texture, state.config.width, state.config.height);
+        state.queue.submit(std::iter::once(encoder.finish()));
+
+        frame
+    }
+
+    // Records the render pass drawing every visible render batch into the target view, which can
+    // be the surface or an offscreen texture. Both views need the size of the surface config, and
+    // the depth view the sample count of the state when MSAA is on
+    pub fn render_to(
+        &self,
+        state: &State,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                // With MSAA, render into the multisampled texture and resolve it into the view
+                view: state.multisampled_framebuffer.as_ref().unwrap_or(view),
+                resolve_target: state.multisampled_framebuffer.as_ref().map(|_| view),
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(state.background_color),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        // Draw all of the render batches in the renderer
+        for render_batch in self.render_batches.iter() {
+            // Skip the rendering if the current render batch is not visible
+            if !render_batch.get_visible() {
+                continue;
+            }
+            let pipeline = render_batch.get_pipeline().unwrap();
+            let vertex_buffer = render_batch.get_vertex_buffer();
+            let index_buffer = render_batch.get_index_buffer();
+
+            // Pass in all of the bind groups
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, render_batch.get_bind_group()
+                .unwrap_or(&state.default_bind_group.default_bindings), &[]);
+            render_pass.set_bind_group(1, &state.spectrogram.bind_group, &[]);
+            render_pass.set_bind_group(2, &state.fft_bins.bind_group, &[]);
+            if let Some(custom_bind_group) = render_batch.get_custom_bind_group() {
+                render_pass.set_bind_group(3, custom_bind_group, &[]);
+            }
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            match render_batch.get_instance_buffer() {
+                None => {}
+                Some(buffer) => {
+                    render_pass.set_vertex_buffer(1, buffer.slice(..));
+                }
+            }
+            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(0..render_batch.get_indices_count(), 0, 0..match render_batch.get_instance_count() {
+                None => {1}
+                Some(t) => {
+                    t as u32}
+            });
+        }
+    }
+}
+
+// A texture the size and format of the surface config that frames can be rendered into and then
+// copied out of
+fn create_capture_texture(state: &State) -> wgpu::Texture {
+    state.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen Texture"),
+        size: wgpu::Extent3d {
+            width: state.config.width,
+            height: state.config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: state.config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    })
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::*;
+
+// A frame copied out of a texture into a buffer that can be read back on the CPU
+pub struct CapturedFrame {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+}
+
+impl CapturedFrame {
+    // Records a copy of the texture into the encoder. The frame can be saved once the encoder
+    // has been submitted
+    pub fn capture(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        // wgpu requires every row of a texture copy to be aligned to 256 bytes
+        let unpadded_bytes_per_row = width * 4;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(alignment) * alignment;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Screenshot Buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Self {
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row,
+        }
+    }
+
+    // Reads the frame back as tightly packed RGBA pixels. Blocks until the GPU is done
+    pub fn read_rgba(&self, device: &wgpu::Device, format: wgpu::TextureFormat, keep_alpha: bool) -> Result<Vec<u8>> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).ok();
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let swap_red_blue = matches!(format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb);
+
+        let mut pixels = Vec::with_capacity((self.width * self.height * 4) as usize);
+        {
+            let data = slice.get_mapped_range();
+            // Strip the row padding
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                for pixel in row[..(self.width * 4) as usize].chunks_exact(4) {
+                    let (r, b) = if swap_red_blue { (pixel[2], pixel[0]) } else { (pixel[0], pixel[2]) };
+                    // Opaque windows ignore the alpha channel, so don't save it either
+                    let a = if keep_alpha { pixel[3] } else { 255 };
+                    pixels.extend_from_slice(&[r, pixel[1], b, a]);
+                }
+            }
+        }
+        self.buffer.unmap();
+
+        Ok(pixels)
+    }
+
+    pub fn save_png(&self, device: &wgpu::Device, format: wgpu::TextureFormat, keep_alpha: bool, path: &Path) -> Result<()> {
+        let pixels = self.read_rgba(device, format, keep_alpha)?;
+        image::save_buffer(path, &pixels, self.width, self.height, image::ColorType::Rgba8)?;
+        Ok(())
+    }
+}
+
+// Timestamped path for a new screenshot inside of the directory
+pub fn screenshot_path(directory: &Path) -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+    directory.join(format!("sound_guy_{}.png", millis))
+}
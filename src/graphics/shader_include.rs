@@ -0,0 +1,104 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const INCLUDE_DIRECTIVE: &str = "#include";
+
+// Replaces every `#include "file.wgsl"` line with the contents of that file, so the uniform
+// declarations can be shared between shaders instead of copied into each one. Paths are relative
+// to the file doing the including. Each file is only inlined the first time it's included, so two
+// files can include the same declarations without redeclaring them
+pub fn resolve_includes(source: &str, file: &str) -> Result<String, String> {
+    let mut included = Vec::new();
+    let mut stack = vec![include_key(Path::new(file))];
+    inline_includes(source, Path::new(file), &mut stack, &mut included)
+}
+
+fn inline_includes(source: &str, file: &Path, stack: &mut Vec<PathBuf>, included: &mut Vec<PathBuf>) -> Result<String, String> {
+    let mut output = String::with_capacity(source.len());
+    for (line_number, line) in source.lines().enumerate() {
+        let include = match parse_include(line) {
+            Some(t) => t.map_err(|e| format!("{}:{}: {}", file.display(), line_number + 1, e))?,
+            None => {
+                output.push_str(line);
+                output.push('\n');
+                continue;
+            }
+        };
+
+        let include_path = match file.parent() {
+            Some(parent) => parent.join(include),
+            None => PathBuf::from(include),
+        };
+
+        let key = include_key(&include_path);
+        if stack.contains(&key) {
+            let chain: Vec<String> = stack.iter().chain([&key]).map(|p| p.display().to_string()).collect();
+            return Err(format!("cyclic include {}", chain.join(" -> ")));
+        }
+        if included.contains(&key) {
+            continue;
+        }
+
+        let include_source = fs::read_to_string(crate::asset_path(&include_path))
+            .map_err(|e| format!("{}:{}: could not include {}: {}", file.display(), line_number + 1, include_path.display(), e))?;
+
+        included.push(key.clone());
+        stack.push(key);
+        output.push_str(&inline_includes(&include_source, &include_path, stack, included)?);
+        stack.pop();
+    }
+    Ok(output)
+}
+
+// The same file can be reached through different paths, e.g. "a.wgsl" and "../shaders/a.wgsl", so
+// files are compared by their canonical path. The built in shader isn't on disk, so paths that
+// can't be canonicalized have their "." and ".." components folded away instead
+fn include_key(path: &Path) -> PathBuf {
+    if let Ok(t) = fs::canonicalize(crate::asset_path(path)) {
+        return t;
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(normalized.components().next_back(), Some(Component::Normal(_))) => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// None when the line isn't an include, otherwise the quoted file name
+fn parse_include(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim().strip_prefix(INCLUDE_DIRECTIVE)?;
+    let file = rest.trim().strip_prefix('"').and_then(|t| t.strip_suffix('"'));
+    Some(file.filter(|t| !t.is_empty()).ok_or(format!("expected {} \"file.wgsl\", got {:?}", INCLUDE_DIRECTIVE, line.trim())))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs;
+    use crate::graphics::shader_include::resolve_includes;
+
+    #[test]
+    fn test_cyclic_shader_include() {
+        let result = resolve_includes("#include \"shader.wgsl\"", "shader.wgsl");
+        assert!(result.unwrap_err().contains("cyclic include"));
+    }
+
+    #[test]
+    fn test_cyclic_shader_include_through_parent_directory() {
+        let directory = std::env::temp_dir().join("sound_guy_test_includes");
+        fs::create_dir_all(&directory).unwrap();
+        let file = directory.join("a.wgsl");
+        let source = "#include \"../sound_guy_test_includes/a.wgsl\"\n";
+        fs::write(&file, source).unwrap();
+
+        let result = resolve_includes(source, file.to_str().unwrap());
+        fs::remove_dir_all(&directory).ok();
+
+        assert!(result.unwrap_err().contains("cyclic include"));
+    }
+}
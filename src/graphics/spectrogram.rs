@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+// Rolling history of the FFT bands, uploaded as a texture with one column per frame and one
+// row per band. Column 0 holds the newest frame, lowest frequency is row 0
+pub struct Spectrogram {
+    texture: wgpu::Texture,
+    pub(crate) bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) bind_group: wgpu::BindGroup,
+    band_count: usize,
+    depth: usize,
+    history: VecDeque<Vec<f32>>,
+}
+
+impl Spectrogram {
+    pub fn new(device: &wgpu::Device, band_count: usize, depth: usize) -> Self {
+        let band_count = band_count.max(1);
+        let depth = depth.max(1);
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Spectrogram Texture"),
+            size: wgpu::Extent3d {
+                width: depth as u32,
+                height: band_count as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // 32 bit float textures can't be filtered, so the sampler has to be nearest
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+            ],
+            label: Some("spectrogram_bind_group_layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("spectrogram_bind_group"),
+        });
+
+        Self {
+            texture,
+            bind_group_layout,
+            bind_group,
+            band_count,
+            depth,
+            history: VecDeque::with_capacity(depth),
+        }
+    }
+
+    // Shifts the bands in as the newest column and uploads the whole history
+    pub fn push(&mut self, queue: &wgpu::Queue, bands: &[f32]) {
+        let mut column = bands.to_vec();
+        column.resize(self.band_count, 0.0);
+        if self.history.len() == self.depth {
+            self.history.pop_back();
+        }
+        self.history.push_front(column);
+
+        let mut texels = vec![0.0f32; self.depth * self.band_count];
+        for (x, column) in self.history.iter().enumerate() {
+            for (y, magnitude) in column.iter().enumerate() {
+                texels[y * self.depth + x] = *magnitude;
+            }
+        }
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&texels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(4 * self.depth as u32),
+                rows_per_image: std::num::NonZeroU32::new(self.band_count as u32),
+            },
+            wgpu::Extent3d {
+                width: self.depth as u32,
+                height: self.band_count as u32,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
@@ -0,0 +1,76 @@
+// Built in shader for MeshRenderType::Lines with a LineWidth. Every segment is drawn as a
+// camera facing quad made out of 2 copies of each end, with the normal attribute holding the
+// position of the other end. LINE_WIDTH is replaced with the width from the avatar settings
+// before the shader is compiled.
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @builtin(vertex_index) vertex_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec3<f32>,
+    @location(2) index: f32,
+    @location(3) other_end: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    // Tint from the module's instance color function, white when it has none
+    @location(9) color: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let line_width = LINE_WIDTH;
+
+    // Corners 0 and 1 are at the start of the segment, 2 and 3 are at the end
+    let corner = model.vertex_index % 4u;
+    var side = 1.0;
+    if (corner == 0u || corner == 3u) {
+        side = -1.0;
+    }
+
+    let world_position = (model_matrix * vec4<f32>(model.position, 1.0)).xyz;
+    let other_position = (model_matrix * vec4<f32>(model.other_end, 1.0)).xyz;
+    var direction = other_position - world_position;
+    if (corner >= 2u) {
+        direction = -direction;
+    }
+
+    // Widen the line perpendicular to both the segment and the direction to the camera
+    let to_camera = camera.view_position.xyz - world_position;
+    let across = normalize(cross(direction, to_camera));
+    let offset = across * side * line_width / 2.0;
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(world_position + offset, 1.0);
+    out.color = model.color * instance.color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(in.color, 0.0);
+}
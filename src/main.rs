@@ -2,15 +2,29 @@
 
 extern crate core;
 
+mod audio;
 mod graphics;
+#[cfg(feature = "midi")]
+mod midi;
+#[cfg(feature = "osc")]
+mod osc;
 
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
 use clap::Parser;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::Stream;
+use cpal::{SampleFormat, Stream};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
+use crate::audio::{AudioCurve, AudioInput, CaptureMode, SimulatedAudio};
+use crate::graphics::{Backend, CameraRotationPath, CompositeAlphaMode, PresentMode};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Settings {
     audio_defuse: f32,
@@ -19,28 +33,363 @@ pub struct Settings {
     resizable: bool,
     default_width: i32,
     default_height: i32,
+    // Smallest size the window can be resized to, in logical pixels
+    #[serde(default = "default_min_size")]
+    min_width: u32,
+    #[serde(default = "default_min_size")]
+    min_height: u32,
     always_on_top: bool,
     title: String,
     camera_rotation: bool,
+    // Number of frequency bands the audio input is split into
+    #[serde(default = "default_fft_bands")]
+    fft_bands: usize,
+    // Number of evenly spaced FFT magnitudes in the storage buffer shaders can read at group 2
+    #[serde(default = "default_fft_bins")]
+    fft_bins: usize,
+    // Share of the previous value the FFT bands and bins keep every 60th of a second, 0 shows
+    // the raw magnitudes and values near 1 react slowly
+    #[serde(default = "default_fft_smoothing")]
+    fft_smoothing: f32,
+    // Samples quieter than this are ignored. Defaults to 0.03
+    #[serde(default = "default_audio_gate")]
+    audio_gate: f32,
+    // Response curve for samples louder than the gate. Defaults to Sqrt
+    #[serde(default = "default_audio_curve")]
+    audio_curve: AudioCurve,
+    // Quietest level the Decibel curve responds to, in dBFS. Defaults to -60. The gate still
+    // applies first, so lower AudioGate too for the floor to matter
+    #[serde(default = "default_audio_decibel_floor")]
+    audio_decibel_floor: f32,
+    // Automatic gain control, scales the audio level so the loudest recent sound is about 1.
+    // The attack and release are the seconds it takes to adjust to louder and quieter input
+    #[serde(default)]
+    audio_agc: bool,
+    #[serde(default = "default_audio_agc_attack")]
+    audio_agc_attack: f32,
+    #[serde(default = "default_audio_agc_release")]
+    audio_agc_release: f32,
+    // How quickly AUDIO_IN rises toward a louder input. Defaults to 1.0 (instantly)
+    #[serde(default = "default_audio_attack")]
+    audio_attack: f32,
+    // How quickly AUDIO_IN falls back toward zero. Defaults to AudioDefuse
+    audio_release: Option<f32>,
+    #[serde(default = "default_camera_speed")]
+    camera_speed: f32,
+    #[serde(default = "default_camera_sensitivity")]
+    camera_sensitivity: f32,
+    // Vertical field of view in degrees
+    #[serde(default = "default_camera_fov")]
+    camera_fov: f32,
+    // Distance of the near and far clip planes, anything outside of them isn't drawn
+    #[serde(default = "default_camera_znear")]
+    camera_znear: f32,
+    #[serde(default = "default_camera_zfar")]
+    camera_zfar: f32,
+    // Radians per second the camera turns around the avatar while CameraRotation is on
+    #[serde(default = "default_camera_rotation_speed")]
+    camera_rotation_speed: f32,
+    // FullSphere also moves the camera up and down, Horizontal turns it like a turntable
+    #[serde(default = "default_camera_rotation_path")]
+    camera_rotation_path: CameraRotationPath,
+    // Where Home puts the camera back to, relative to the avatar. The starting distance when not set
+    camera_home: Option<[f32; 3]>,
+    // Closest and furthest the camera can zoom to from the look target
+    #[serde(default = "default_camera_min_radius")]
+    camera_min_radius: f32,
+    #[serde(default = "default_camera_max_radius")]
+    camera_max_radius: f32,
+    // Point the camera orbits around and looks at, for avatars that aren't centered on the origin
+    #[serde(default)]
+    look_target: [f32; 3],
+    // Directory that F12 screenshots are saved to
+    #[serde(default = "default_screenshot_dir")]
+    screenshot_dir: String,
+    // Fifo waits for vsync, Mailbox and Immediate allow lower latency and uncapped frame rates
+    #[serde(default = "default_present_mode")]
+    present_mode: PresentMode,
+    // Auto, Vulkan, Dx12, Metal or Gl, for working around driver bugs in one of them
+    #[serde(default = "default_backend")]
+    backend: Backend,
+    // Auto, Opaque, PreMultiplied, PostMultiplied or Inherit. Modes the surface doesn't support
+    // are ignored
+    #[serde(default = "default_composite_alpha_mode")]
+    composite_alpha_mode: CompositeAlphaMode,
+    // Alpha blended modules discard fragments with less alpha than this, in shaders that compare
+    // against ALPHA_CUTOFF. Takes effect when the shaders are next built, e.g. with F5
+    #[serde(default)]
+    alpha_cutoff: f32,
+    // MSAA samples per pixel, 1 disables anti-aliasing
+    #[serde(default = "default_msaa_samples")]
+    msaa_samples: u32,
+    #[serde(default = "default_capture_mode")]
+    capture_mode: CaptureMode,
+    // Devices whose levels are added up by weight into the audio uniform, e.g.
+    // [{"Device": "Microphone", "Weight": 1.0}, {"Device": "Line In", "Weight": 0.5}].
+    // The first one also drives the frequency bands. Replaces --input-device when not empty
+    #[serde(default)]
+    audio_inputs: Vec<AudioInput>,
+    // Start with the window ignoring the mouse so clicks go to the windows below it
+    #[serde(default)]
+    click_through: bool,
+    // Where the window opens in logical pixels from the top left of the monitor, and which
+    // monitor it opens on. The OS decides when none of them are set
+    window_x: Option<i32>,
+    window_y: Option<i32>,
+    monitor_index: Option<usize>,
+    // Avatar files that [ and ] switch between
+    #[serde(default)]
+    avatar_presets: Vec<String>,
+    // How many times louder than the last second a block has to be to count as a beat
+    #[serde(default = "default_beat_sensitivity")]
+    beat_sensitivity: f32,
+    // Seconds the peak hold uniform stays at a new peak, then the seconds it takes to fall from 1 to 0
+    #[serde(default = "default_peak_hold_time")]
+    peak_hold_time: f32,
+    #[serde(default = "default_peak_decay_time")]
+    peak_decay_time: f32,
+    // Number of frames of FFT bands kept in the spectrogram texture
+    #[serde(default = "default_spectrogram_history")]
+    spectrogram_history: usize,
+    // Image drawn behind the avatar when the background isn't transparent
+    background_image: Option<String>,
+    // How many units the shader time advances every second
+    #[serde(default = "default_time_scale")]
+    time_scale: f32,
+    // The shader time wraps back to 0 when it reaches this, so it doesn't lose precision over a
+    // long session. Grows forever when not set
+    time_loop_period: Option<f32>,
+    // Cap on the number of frames drawn per second, unlimited when not set
+    max_fps: Option<u32>,
+    // UDP port and address the OSC listener takes float values from
+    #[cfg(feature = "osc")]
+    #[serde(default = "default_osc_port")]
+    osc_port: u16,
+    #[cfg(feature = "osc")]
+    #[serde(default = "default_osc_address")]
+    osc_address: String,
+    // Name (or part of it) of the MIDI input port, the first port is used when not set
+    #[cfg(feature = "midi")]
+    midi_port: Option<String>,
+    // Controls that are passed to the shaders, e.g. [{"Cc": 1}, {"Note": 60}]
+    #[cfg(feature = "midi")]
+    #[serde(default)]
+    midi_inputs: Vec<midi::MidiControl>,
+}
+
+fn default_fft_bands() -> usize { 8 }
+fn default_min_size() -> u32 { 64 }
+fn default_fft_bins() -> usize { 64 }
+fn default_fft_smoothing() -> f32 { 0.5 }
+fn default_audio_gate() -> f32 { 0.03 }
+fn default_audio_curve() -> AudioCurve { AudioCurve::Sqrt }
+fn default_audio_decibel_floor() -> f32 { -60.0 }
+fn default_audio_agc_attack() -> f32 { 0.1 }
+fn default_audio_agc_release() -> f32 { 5.0 }
+fn default_audio_attack() -> f32 { 1.0 }
+fn default_camera_speed() -> f32 { 4.0 }
+fn default_camera_sensitivity() -> f32 { 0.4 }
+fn default_camera_fov() -> f32 { 45.0 }
+fn default_camera_znear() -> f32 { 0.1 }
+fn default_camera_zfar() -> f32 { 100.0 }
+fn default_camera_rotation_speed() -> f32 { 1.0 }
+fn default_camera_min_radius() -> f32 { 0.5 }
+fn default_camera_max_radius() -> f32 { 50.0 }
+fn default_camera_rotation_path() -> CameraRotationPath { CameraRotationPath::FullSphere }
+fn default_screenshot_dir() -> String { String::from("screenshots") }
+fn default_present_mode() -> PresentMode { PresentMode::Fifo }
+fn default_backend() -> Backend { Backend::Auto }
+fn default_composite_alpha_mode() -> CompositeAlphaMode { CompositeAlphaMode::Auto }
+fn default_msaa_samples() -> u32 { 1 }
+// Time used to advance 0.05 every frame, which is 3 per second at 60 fps
+fn default_time_scale() -> f32 { 3.0 }
+fn default_capture_mode() -> CaptureMode { CaptureMode::Input }
+fn default_spectrogram_history() -> usize { 64 }
+fn default_beat_sensitivity() -> f32 { 1.5 }
+fn default_peak_hold_time() -> f32 { 1.0 }
+fn default_peak_decay_time() -> f32 { 2.0 }
+#[cfg(feature = "osc")]
+fn default_osc_port() -> u16 { 9000 }
+#[cfg(feature = "osc")]
+fn default_osc_address() -> String { String::from("/sound_guy/value") }
+
+// Used when there is no settings file so a fresh clone starts without any setup
+const BUILT_IN_SETTINGS: &str = include_str!("../settings.json");
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::Io(path, e) => write!(f, "Could not load settings from {}: {}", path.display(), e),
+            SettingsError::Parse(path, e) => write!(f, "{} was not well-formatted: {}", path.display(), e),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            audio_defuse: 0.00005,
+            transparent_background: true,
+            background_color: vec![0.0, 0.0, 0.0, 0.0],
+            resizable: true,
+            default_width: 400,
+            default_height: 400,
+            min_width: default_min_size(),
+            min_height: default_min_size(),
+            always_on_top: true,
+            title: String::from("Sound Guy"),
+            camera_rotation: true,
+            fft_bands: default_fft_bands(),
+            fft_bins: default_fft_bins(),
+            fft_smoothing: default_fft_smoothing(),
+            audio_gate: default_audio_gate(),
+            audio_curve: default_audio_curve(),
+            audio_decibel_floor: default_audio_decibel_floor(),
+            audio_agc: false,
+            audio_agc_attack: default_audio_agc_attack(),
+            audio_agc_release: default_audio_agc_release(),
+            audio_attack: default_audio_attack(),
+            audio_release: None,
+            camera_speed: default_camera_speed(),
+            camera_rotation_speed: default_camera_rotation_speed(),
+            camera_rotation_path: default_camera_rotation_path(),
+            camera_home: None,
+            camera_min_radius: default_camera_min_radius(),
+            camera_max_radius: default_camera_max_radius(),
+            look_target: [0.0; 3],
+            camera_sensitivity: default_camera_sensitivity(),
+            camera_fov: default_camera_fov(),
+            camera_znear: default_camera_znear(),
+            camera_zfar: default_camera_zfar(),
+            screenshot_dir: default_screenshot_dir(),
+            present_mode: default_present_mode(),
+            backend: default_backend(),
+            composite_alpha_mode: default_composite_alpha_mode(),
+            alpha_cutoff: 0.0,
+            msaa_samples: default_msaa_samples(),
+            capture_mode: default_capture_mode(),
+            audio_inputs: Vec::new(),
+            click_through: false,
+            window_x: None,
+            window_y: None,
+            monitor_index: None,
+            avatar_presets: Vec::new(),
+            spectrogram_history: default_spectrogram_history(),
+            beat_sensitivity: default_beat_sensitivity(),
+            peak_hold_time: default_peak_hold_time(),
+            peak_decay_time: default_peak_decay_time(),
+            background_image: None,
+            time_scale: default_time_scale(),
+            time_loop_period: None,
+            max_fps: None,
+            #[cfg(feature = "osc")]
+            osc_port: default_osc_port(),
+            #[cfg(feature = "osc")]
+            osc_address: default_osc_address(),
+            #[cfg(feature = "midi")]
+            midi_port: None,
+            #[cfg(feature = "midi")]
+            midi_inputs: Vec::new(),
+        }
+    }
 }
 
 impl Settings {
-    fn load_settings() -> Settings {
+    fn load_settings(path: &Path) -> Result<Settings, SettingsError> {
         // Load file as string
-        let mut file = match fs::read_to_string("settings.json") {
-            Ok(t) => {t}
-            Err(_) => {panic!("Could not load settings from settings.json")}
-        };
+        let file = fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
 
         println!("Settings: {}", file);
 
         // Load file as json
-        let json : Settings = serde_json::from_str(&file).expect("JSON was not well-formatted");
-        return json;
+        serde_json::from_str(&file).map_err(|e| SettingsError::Parse(path.to_path_buf(), e))
+    }
+
+    // Loads the settings file, falling back to the built in settings when there is no file and to the
+    // default settings if it can't be loaded
+    fn load_settings_or_default(path: &Path) -> Settings {
+        match Settings::load_settings(path) {
+            Ok(t) => t,
+            Err(SettingsError::Io(_, e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("Warning: No settings file at {}, using the built in settings. Create it to override them", path.display());
+                serde_json::from_str(BUILT_IN_SETTINGS).unwrap_or_default()
+            }
+            Err(e) => {
+                eprintln!("Warning: {}. Using the default settings", e);
+                Settings::default()
+            }
+        }
+    }
+
+    fn audio_release(&self) -> f32 {
+        self.audio_release.unwrap_or(self.audio_defuse)
+    }
+}
+
+// The settings as they were last loaded, including changes picked up by the SettingsWatcher, so
+// the audio thread rebuilds its streams with the current values when it reconnects
+static CURRENT_SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+// Watches the settings file so that changes can be applied while the app is running
+pub struct SettingsWatcher {
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<()>,
+    path: PathBuf,
+}
+
+impl SettingsWatcher {
+    pub fn new(path: &Path) -> notify::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let file_name = path.file_name().map(PathBuf::from).unwrap_or_default();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            if let Ok(event) = res {
+                if event.kind.is_modify() || event.kind.is_create() {
+                    if event.paths.iter().any(|path| path.ends_with(&file_name)) {
+                        sender.send(()).ok();
+                    }
+                }
+            }
+        })?;
+        // Watch the directory instead of the file since editors often replace the file on save
+        let directory = match path.parent() {
+            Some(t) if !t.as_os_str().is_empty() => t,
+            _ => Path::new("."),
+        };
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            receiver,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Returns the reloaded settings if the settings file has changed since the last call
+    pub fn poll(&self) -> Option<Settings> {
+        if self.receiver.try_iter().count() == 0 {
+            return None;
+        }
+
+        match Settings::load_settings(&self.path) {
+            Ok(t) => {
+                *CURRENT_SETTINGS.lock().unwrap() = Some(t.clone());
+                Some(t)
+            }
+            Err(e) => {
+                eprintln!("Warning: {}. Keeping the current settings", e);
+                None
+            }
+        }
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about = "CPAL feedback example", long_about = None)]
 struct Opt {
     /// The input audio device to use
@@ -51,6 +400,63 @@ struct Opt {
     #[arg(short, long, value_name = "DELAY_MS", default_value_t = 150.0)]
     latency: f32,
 
+    /// Capture what the system is playing instead of the input device
+    #[arg(long)]
+    loopback: bool,
+
+    /// Sample rate to open the input with, the device's default when not set
+    #[arg(long, value_name = "HZ")]
+    sample_rate: Option<u32>,
+
+    /// Number of channels to open the input with, the device's default when not set
+    #[arg(long)]
+    channels: Option<u16>,
+
+    /// Avatar settings to load, so several avatars can be kept side by side
+    #[arg(long, value_name = "PATH", default_value = graphics::DEFAULT_AVATAR_DATA_PATH)]
+    avatar: PathBuf,
+
+    /// Directory the settings, avatar, shaders and other resources are loaded from. Defaults to
+    /// the directory of the executable, files that aren't there are loaded from the working directory
+    #[arg(long, value_name = "DIR")]
+    assets_dir: Option<PathBuf>,
+
+    /// Settings file to load and watch for changes
+    #[arg(long, value_name = "PATH", default_value = "settings.json")]
+    settings: PathBuf,
+
+    /// Render one frame of the avatar into this PNG without opening a window, then exit
+    #[arg(long, value_name = "OUTPUT")]
+    render_frame: Option<PathBuf>,
+
+    /// Width of the --render-frame image, DefaultWidth when not set
+    #[arg(long, requires = "render_frame")]
+    width: Option<u32>,
+
+    /// Height of the --render-frame image, DefaultHeight when not set
+    #[arg(long, requires = "render_frame")]
+    height: Option<u32>,
+
+    /// Record the visualizer into this directory as a numbered PNG sequence, then exit
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+
+    /// Seconds to --record for
+    #[arg(long, value_name = "SECS", default_value = "5", value_parser = parse_seconds)]
+    duration: Duration,
+
+    /// Frame rate of the --record image sequence
+    #[arg(long, value_name = "FPS", default_value_t = 30)]
+    record_fps: u32,
+
+    /// Drive the visualizer with generated audio instead of an input device
+    #[arg(long, value_name = "PATTERN")]
+    simulate_audio: Option<SimulatedAudio>,
+
+    /// List every available input host and device, then exit
+    #[arg(long)]
+    list_devices: bool,
+
     /// Use the JACK host
     #[cfg(all(
     any(
@@ -66,29 +472,256 @@ struct Opt {
     jack: bool,
 }
 
+// Seconds as a Duration, rejecting negative, infinite and NaN values that Duration can't hold
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f32 = value.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f32(seconds).map_err(|_| format!("expected a finite, non-negative number of seconds, got {}", value))
+}
+
+// f32 that can be shared between the audio callback thread and the render loop.
+// The value is stored as its bit pattern inside of an AtomicU32.
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub const fn zero() -> Self {
+        // 0u32 is the bit pattern of 0.0f32
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
 // Float that stores the loudest audio input detected over the las few milliseconds
-pub static mut AUDIO_IN: f32 = 0.0;
+pub static AUDIO_IN: AtomicF32 = AtomicF32::zero();
+// Release rate of AUDIO_IN, shared so it can be changed when settings.json is reloaded
+pub static AUDIO_RELEASE: AtomicF32 = AtomicF32::zero();
+// Levels of the left and right channel, both follow the only channel of a mono stream
+pub static AUDIO_LEFT: AtomicF32 = AtomicF32::zero();
+pub static AUDIO_RIGHT: AtomicF32 = AtomicF32::zero();
+// Last value received over OSC, stays 0 without the osc feature
+pub static OSC_IN: AtomicF32 = AtomicF32::zero();
+
+// Directory that relative resource paths are looked up in, from --assets-dir
+static ASSETS_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Resolves a resource such as a shader, model or image against the assets directory. Relative
+// paths that don't exist there are left relative to the working directory, so running from the
+// source tree still works
+pub fn asset_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    if let Some(assets_dir) = ASSETS_DIR.get() {
+        let asset = assets_dir.join(path);
+        if path.is_relative() && asset.exists() {
+            return asset;
+        }
+    }
+    path.to_path_buf()
+}
+
+// Release builds on Windows have no console, so fatal errors and panics are also written to a
+// file next to the executable instead of disappearing with stderr
+#[cfg(all(windows, not(debug_assertions)))]
+const ERROR_LOG_FILE: &str = "sound_guy_error.log";
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn write_error_log(message: &str) {
+    if let Ok(exe) = std::env::current_exe() {
+        let _ = std::fs::write(exe.with_file_name(ERROR_LOG_FILE), format!("{}\n", message));
+    }
+}
+
+pub fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", error);
+    #[cfg(all(windows, not(debug_assertions)))]
+    write_error_log(&format!("Error: {}", error));
+    std::process::exit(1);
+}
 
 fn main() {
-    let settings = Settings::load_settings();
+    #[cfg(all(windows, not(debug_assertions)))]
+    {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            write_error_log(&info.to_string());
+            default_hook(info);
+        }));
+    }
+
+    env_logger::init();
+    let mut opt = Opt::parse();
+
+    let assets_dir = opt.assets_dir.clone().or_else(|| {
+        std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf))
+    });
+    if let Some(assets_dir) = assets_dir {
+        ASSETS_DIR.set(assets_dir).ok();
+    }
+    opt.settings = asset_path(&opt.settings);
+    opt.avatar = asset_path(&opt.avatar);
+
+    if opt.list_devices {
+        list_devices();
+        return;
+    }
+
+    let settings = Settings::load_settings_or_default(&opt.settings);
     println!("{:?}", settings);
+    *CURRENT_SETTINGS.lock().unwrap() = Some(settings.clone());
+    // Set once here and then by apply_settings, rebuilding the streams doesn't touch it
+    AUDIO_RELEASE.store(settings.audio_release());
+
+    if let Some(path) = &opt.render_frame {
+        let width = opt.width.unwrap_or(settings.default_width.max(1) as u32);
+        let height = opt.height.unwrap_or(settings.default_height.max(1) as u32);
+        match pollster::block_on(graphics::render_frame(&opt, &settings, path, width, height)) {
+            Ok(_) => println!("Saved frame to {}", path.display()),
+            Err(e) => exit_with_error(format!("could not render frame to {}: {}", path.display(), e)),
+        }
+        return;
+    }
 
     // TODO: use settings during initialization
 
-    // Setup the audio stream
-    let stream = setup_feedback(&settings);
+    // Setup the audio stream, simulated audio is generated by the render loop instead
+    if opt.simulate_audio.is_none() {
+        if let Err(e) = spawn_audio_thread(opt.clone(), settings.clone()) {
+            exit_with_error(e);
+        }
+    }
+
+    #[cfg(feature = "osc")]
+    if let Err(e) = osc::spawn_listener(settings.osc_port, settings.osc_address.clone()) {
+        eprintln!("Warning: Could not listen for OSC on port {}: {}", settings.osc_port, e);
+    }
 
     // Setup the window and graphics
-    pollster::block_on(graphics::run(&settings));
+    pollster::block_on(graphics::run(&opt, &settings));
+}
+
+// Prints every input device of every available host, marking the host's default device
+fn list_devices() {
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        println!("Host: {}", host_id.name());
 
-    // Destroy the audio steam
-    drop(stream);
+        let default_name = host.default_input_device().and_then(|device| device.name().ok());
+        let devices = match host.input_devices() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+
+        for device in devices {
+            let name = match device.name() {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            let marker = if Some(&name) == default_name.as_ref() { " (default)" } else { "" };
+            match device.default_input_config() {
+                Ok(config) => println!("    \"{}\"{} - {} Hz, {} channel(s)",
+                                       name, marker, config.sample_rate().0, config.channels()),
+                Err(_) => println!("    \"{}\"{} - no default input config", name, marker),
+            }
+        }
+    }
 }
 
-// Consumes the thread until done with feedback
-fn setup_feedback(settings: &Settings) -> Stream {
-    let opt = Opt::parse();
+#[derive(Debug)]
+pub enum AudioError {
+    NoDevice {name: String, available: Vec<String>},
+    ConfigUnavailable(cpal::DefaultStreamConfigError),
+    BuildFailed(cpal::BuildStreamError),
+    PlayFailed(cpal::PlayStreamError),
+    ThreadStopped,
+}
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioError::NoDevice {name, available} => {
+                write!(f, "Could not find input device \"{}\". Available input devices:", name)?;
+                for device in available {
+                    write!(f, "\n    \"{}\"", device)?;
+                }
+                Ok(())
+            }
+            AudioError::ConfigUnavailable(e) => write!(f, "The input device has no usable config: {}", e),
+            AudioError::BuildFailed(e) => write!(f, "Could not open the audio stream: {}", e),
+            AudioError::PlayFailed(e) => write!(f, "Could not start the audio stream: {}", e),
+            AudioError::ThreadStopped => write!(f, "The audio thread stopped before starting the streams"),
+        }
+    }
+}
 
+// Set by the stream error callback, so the audio thread knows to rebuild the streams
+static AUDIO_STREAM_LOST: AtomicBool = AtomicBool::new(false);
+
+// How often the audio thread checks on the streams, and how long it waits between attempts to
+// reconnect, doubling up to the max while the device stays missing
+const AUDIO_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+const AUDIO_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const AUDIO_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(16);
+
+// Keeps the streams on their own thread, which rebuilds them when the input device goes away,
+// e.g. a USB interface being unplugged, while the avatar keeps rendering. Returns once the first
+// streams are playing, or with the error if they couldn't be started
+fn spawn_audio_thread(opt: Opt, settings: Settings) -> Result<(), AudioError> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut streams = match setup_feedback(&opt, &settings) {
+            Ok(t) => t,
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return;
+            }
+        };
+        let _ = sender.send(Ok(()));
+
+        loop {
+            thread::sleep(AUDIO_CHECK_INTERVAL);
+            if !AUDIO_STREAM_LOST.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+
+            // Silence the levels instead of leaving them frozen at the last value
+            eprintln!("Warning: Lost the audio input, reconnecting");
+            drop(streams);
+            AUDIO_IN.store(0.0);
+            AUDIO_LEFT.store(0.0);
+            AUDIO_RIGHT.store(0.0);
+
+            // Looking the device up again also picks up a new default device
+            let mut delay = AUDIO_RECONNECT_DELAY;
+            streams = loop {
+                thread::sleep(delay);
+                let settings = CURRENT_SETTINGS.lock().unwrap().clone().unwrap_or_else(|| settings.clone());
+                match setup_feedback(&opt, &settings) {
+                    Ok(t) => break t,
+                    Err(e) => {
+                        delay = (delay * 2).min(AUDIO_MAX_RECONNECT_DELAY);
+                        eprintln!("Warning: Could not reconnect the audio input, retrying in {}s: {}", delay.as_secs(), e);
+                    }
+                }
+            };
+            // Errors from the old streams don't count against the new ones
+            AUDIO_STREAM_LOST.store(false, Ordering::Relaxed);
+            println!("Reconnected the audio input");
+        }
+    });
+
+    receiver.recv().unwrap_or(Err(AudioError::ThreadStopped))
+}
+
+// Consumes the thread until done with feedback
+fn setup_feedback(opt: &Opt, settings: &Settings) -> Result<Vec<Stream>, AudioError> {
     // Conditionally compile with jack if the feature is specified.
     #[cfg(all(
     any(
@@ -123,60 +756,260 @@ fn setup_feedback(settings: &Settings) -> Stream {
     ))]
         let host = cpal::default_host();
 
-    // Find devices.
-    let input_device = host.default_input_device()
-        .expect("failed to find input device");
+    // Either every device from AudioInputs, or a single device from the command line
+    let mut inputs = Vec::new();
+    if settings.audio_inputs.is_empty() {
+        // Loopback captures are already configured, everything else goes through the input device
+        let loopback = opt.loopback || settings.capture_mode == CaptureMode::Loopback;
+        let loopback_device = if loopback { find_loopback_device(&host) } else { None };
+        if loopback && loopback_device.is_none() {
+            eprintln!("Warning: Loopback capture isn't supported by the {} host, using the input device instead",
+                      host.id().name());
+        }
+        let (device, supported_config) = match loopback_device {
+            Some(t) => t,
+            None => find_input_device(&host, &opt.input_device)?,
+        };
+        inputs.push((device, supported_config, 1.0));
+    } else {
+        for audio_input in settings.audio_inputs.iter() {
+            let (device, supported_config) = find_input_device(&host, &audio_input.device)?;
+            inputs.push((device, supported_config, audio_input.weight));
+        }
+    }
 
-    println!("Using input device: \"{}\"", match input_device.name() {
-        Ok(t) => t,
-        Err(_) => panic!("ERROR")
-    });
+    let mix = Arc::new(audio::InputMix::new(inputs.iter().map(|(_, _, weight)| *weight).collect()));
 
-    // We'll try and use the same configuration between streams to keep it simple.
-    let config: cpal::StreamConfig = match input_device.default_input_config() {
-        Ok(t) => t.into(),
-        Err(_) => panic!("Config is brok")
+    // The first input also drives the frequency bands, stereo levels and waveform
+    let mut streams = Vec::new();
+    for (index, (device, supported_config, _)) in inputs.into_iter().enumerate() {
+        let supported_config = override_stream_config(&device, supported_config, opt.sample_rate, opt.channels);
+        streams.push(build_feedback_stream(&device, supported_config, settings, mix.clone(), index)?);
+    }
+
+    // Play the streams.
+    println!(
+        "Starting the input and output streams with `{}` milliseconds of latency.",
+        opt.latency
+    );
+
+    for stream in streams.iter() {
+        stream.play().map_err(AudioError::PlayFailed)?;
+    }
+
+    //thread::sleep(time::Duration::from_millis(10000));
+
+    Ok(streams)
+}
+
+// Builds the stream for one input, which mixes its level into AUDIO_IN at `index` of the mix
+fn build_feedback_stream(
+    device: &cpal::Device,
+    supported_config: cpal::SupportedStreamConfig,
+    settings: &Settings,
+    mix: Arc<audio::InputMix>,
+    index: usize,
+) -> Result<Stream, AudioError> {
+    let sample_format = supported_config.sample_format();
+    let config: cpal::StreamConfig = supported_config.into();
+
+    let audio_attack = settings.audio_attack;
+    let audio_gate = settings.audio_gate;
+    let audio_curve = settings.audio_curve;
+    let audio_decibel_floor = settings.audio_decibel_floor;
+    let primary = index == 0;
+    let mut band_analyzer = audio::BandAnalyzer::new(settings.fft_bands, settings.fft_bins, settings.beat_sensitivity);
+    let channels = (config.channels as usize).max(1);
+    let sample_rate = config.sample_rate.0 as f32;
+    let mut auto_gain = if settings.audio_agc {
+        Some(audio::AutoGain::new(settings.audio_agc_attack, settings.audio_agc_release))
+    } else {
+        None
     };
 
-    let audio_defuse = settings.audio_defuse;
+    // Moves the level toward the input at the attack rate if the input is louder,
+    // otherwise decrease it gradually at the release rate. Decibel follows the plain amplitude
+    // without the gate, it's the envelope that gets converted to dBFS by `output`
+    let envelope = move |level: f32, sample: f32| {
+        let target = match audio_curve {
+            AudioCurve::Decibel => sample.abs(),
+            _ if sample < audio_gate => 0.0,
+            _ => audio_curve.apply(sample, audio_decibel_floor),
+        };
+        if target > level {
+            level + (target - level) * audio_attack
+        } else {
+            level - level * AUDIO_RELEASE.load()
+        }
+    };
+    let output = move |level: f32| match audio_curve {
+        AudioCurve::Decibel => audio_curve.apply(level, audio_decibel_floor),
+        _ => level,
+    };
 
     // Call back for when the audio input device get audio
-    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| unsafe {
+    let mut audio_in = 0.0;
+    let mut audio_left = 0.0;
+    let mut audio_right = 0.0;
+    let mut mono: Vec<f32> = Vec::new();
+    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
         for &sample in data {
+            audio_in = envelope(audio_in, sample);
+        }
+        // The gain is applied after the envelope so it doesn't change how the envelope moves
+        let level = match auto_gain.as_mut() {
+            Some(auto_gain) => auto_gain.apply(output(audio_in), (data.len() / channels) as f32 / sample_rate),
+            None => output(audio_in),
+        };
+        AUDIO_IN.store(mix.set_level(index, level));
+
+        if !primary {
+            return;
+        }
+
+        // The analysis wants one sample per frame, interleaved channels would halve the time a
+        // block covers and mirror the spectrum into the treble
+        mono.clear();
+        mono.extend(data.chunks(channels).map(|frame| frame.iter().sum::<f32>() / frame.len() as f32));
+        for &sample in &mono {
+            band_analyzer.push_sample(sample);
+        }
 
-            // Increases AUDIO_IN if the input is louder and decrease it gradually
-            //let var = if sample < 0.1 {0.0} else {}
-            AUDIO_IN = f32::max(AUDIO_IN, if sample < 0.03 {0.0} else {f32::sqrt(sample*2.0)}) - AUDIO_IN * audio_defuse;
+        // Samples are interleaved with the left channel first, mono streams drive both sides
+        for frame in data.chunks(channels) {
+            audio_left = envelope(audio_left, frame[0]);
+            audio_right = envelope(audio_right, *frame.get(1).unwrap_or(&frame[0]));
         }
+        AUDIO_LEFT.store(output(audio_left));
+        AUDIO_RIGHT.store(output(audio_right));
+
+        audio::push_recent_samples(&mono);
     };
 
     // Build streams.
     println!(
-        "Attempting to build both streams with f32 samples and `{:?}`.",
-        config
+        "Attempting to build both streams with {:?} samples and `{:?}`.",
+        sample_format, config
     );
-    let input_stream = match input_device.build_input_stream(&config, input_data_fn, err_fn) {
-        Ok(t) => t,
-        Err(_) => panic!("NOOOOOO!")
+    let input_stream = match sample_format {
+        SampleFormat::F32 => build_input_stream::<f32>(device, &config, input_data_fn),
+        SampleFormat::I16 => build_input_stream::<i16>(device, &config, input_data_fn),
+        SampleFormat::U16 => build_input_stream::<u16>(device, &config, input_data_fn),
     };
+    let input_stream = input_stream.map_err(AudioError::BuildFailed)?;
     println!("Successfully built streams.");
 
-    // Play the streams.
-    println!(
-        "Starting the input and output streams with `{}` milliseconds of latency.",
-        opt.latency
-    );
+    Ok(input_stream)
+}
 
+// Builds a stream in the device's own sample format, converting the samples to f32 before they
+// are passed to the callback
+fn build_input_stream<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut callback: impl FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+) -> Result<Stream, cpal::BuildStreamError> {
+    let mut samples: Vec<f32> = Vec::new();
+    device.build_input_stream(config, move |data: &[T], info: &cpal::InputCallbackInfo| {
+        samples.clear();
+        samples.extend(data.iter().map(cpal::Sample::to_f32));
+        callback(&samples, info);
+    }, err_fn)
+}
 
-    input_stream.play().expect("TODO: panic message");
+// Applies --sample-rate and --channels to the device's default config, keeping the default with a
+// warning when the device has no config that supports them
+fn override_stream_config(
+    device: &cpal::Device,
+    default_config: cpal::SupportedStreamConfig,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+) -> cpal::SupportedStreamConfig {
+    if sample_rate.is_none() && channels.is_none() {
+        return default_config;
+    }
+    let sample_rate = sample_rate.unwrap_or(default_config.sample_rate().0);
+    let channels = channels.unwrap_or(default_config.channels());
+
+    // A WASAPI loopback is opened on an output device, so it only has output configs
+    let mut ranges: Vec<cpal::SupportedStreamConfigRange> = device.supported_input_configs()
+        .map(|configs| configs.collect())
+        .unwrap_or_default();
+    if ranges.is_empty() {
+        ranges = device.supported_output_configs()
+            .map(|configs| configs.collect())
+            .unwrap_or_default();
+    }
 
-    //thread::sleep(time::Duration::from_millis(10000));
+    // Keep the default sample format when there's a choice
+    ranges.sort_by_key(|range| range.sample_format() != default_config.sample_format());
+    let range = ranges.into_iter().find(|range| range.channels() == channels
+        && range.min_sample_rate().0 <= sample_rate && sample_rate <= range.max_sample_rate().0);
+
+    match range {
+        Some(range) => range.with_sample_rate(cpal::SampleRate(sample_rate)),
+        None => {
+            eprintln!("Warning: \"{}\" doesn't support {} channels at {} Hz, using its default config",
+                      device.name().unwrap_or_default(), channels, sample_rate);
+            default_config
+        }
+    }
+}
+
+// WASAPI captures what an output device plays when an input stream is built on it. Other hosts
+// list their loopback as input devices instead, like the "Monitor of" devices from PulseAudio
+fn find_loopback_device(host: &cpal::Host) -> Option<(cpal::Device, cpal::SupportedStreamConfig)> {
+    #[cfg(target_os = "windows")]
+    if host.id() == cpal::HostId::Wasapi {
+        let device = host.default_output_device()?;
+        let config = device.default_output_config().ok()?;
+        println!("Capturing loopback of output device: \"{}\"", device.name().unwrap_or_default());
+        return Some((device, config));
+    }
 
-    input_stream
+    let device = host.input_devices().ok()?
+        .find(|device| device.name().map(|name| name.to_lowercase().contains("monitor")).unwrap_or(false))?;
+    let config = device.default_input_config().ok()?;
+    println!("Capturing loopback device: \"{}\"", device.name().unwrap_or_default());
+    Some((device, config))
 }
 
+// Finds the input device with the name, or the default one for "default", with its default config
+fn find_input_device(host: &cpal::Host, name: &str) -> Result<(cpal::Device, cpal::SupportedStreamConfig), AudioError> {
+    // Find devices.
+    let input_device = if name == "default" {
+        host.default_input_device()
+    } else {
+        host.input_devices()
+            .ok()
+            .and_then(|mut devices| devices.find(|device| device.name().map(|device_name| device_name == name).unwrap_or(false)))
+    };
 
+    let input_device = match input_device {
+        Some(t) => t,
+        None => {
+            let available = host.input_devices()
+                .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+                .unwrap_or_default();
+            return Err(AudioError::NoDevice {name: name.to_string(), available});
+        }
+    };
+
+    println!("Using input device: \"{}\"", input_device.name().unwrap_or_default());
 
-fn err_fn(_: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", "Audio input stream");
+    // We'll try and use the same configuration between streams to keep it simple.
+    let config = input_device.default_input_config().map_err(AudioError::ConfigUnavailable)?;
+
+    Ok((input_device, config))
+}
+
+
+
+// Only a device that's gone needs the streams rebuilt, backend errors are usually one off glitches
+// that the stream carries on from
+fn err_fn(e: cpal::StreamError) {
+    eprintln!("an error occurred on stream: {}: {}", "Audio input stream", e);
+    if let cpal::StreamError::DeviceNotAvailable = e {
+        AUDIO_STREAM_LOST.store(true, Ordering::Relaxed);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use serde::Deserialize;
+
+// Latest value of every configured MIDI input, in the order of the MidiInputs setting
+pub static MIDI_VALUES: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+
+// A MIDI control that drives one float in the MIDI uniform, e.g. {"Cc": 1} or {"Note": 60}
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum MidiControl {
+    // Value of a control change message, scaled to 0..1
+    Cc(u8),
+    // Velocity of the note while it is held, 0 once released
+    Note(u8),
+}
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+// Connects to the first port whose name contains `port_name`, or the first port at all when
+// no name is given. The connection stays open until the returned value is dropped
+pub fn connect(port_name: Option<&str>, controls: Vec<MidiControl>) -> Result<MidiInputConnection<()>, String> {
+    let mut midi_in = MidiInput::new("sound_guy").map_err(|e| e.to_string())?;
+    midi_in.ignore(Ignore::All);
+
+    let ports = midi_in.ports();
+    let port = ports.iter()
+        .find(|port| match port_name {
+            Some(name) => midi_in.port_name(port).map(|n| n.contains(name)).unwrap_or(false),
+            None => true,
+        })
+        .ok_or_else(|| match port_name {
+            Some(name) => format!("No MIDI input port matching \"{}\"", name),
+            None => String::from("No MIDI input ports available"),
+        })?;
+    println!("Using MIDI input: {}", midi_in.port_name(port).unwrap_or_default());
+
+    *MIDI_VALUES.lock().unwrap() = vec![0.0; controls.len()];
+
+    midi_in.connect(port, "sound_guy_input", move |_, message, _| {
+        handle_message(message, &controls);
+    }, ()).map_err(|e| e.to_string())
+}
+
+fn handle_message(message: &[u8], controls: &[MidiControl]) {
+    let (status, number, value) = match message {
+        [status, number, value, ..] => (status & 0xF0, *number, *value),
+        _ => return,
+    };
+    let mut values = MIDI_VALUES.lock().unwrap();
+    for (control, out) in controls.iter().zip(values.iter_mut()) {
+        match (control, status) {
+            (MidiControl::Cc(cc), CONTROL_CHANGE) if *cc == number => *out = value as f32 / 127.0,
+            // A note on with velocity 0 is a note off
+            (MidiControl::Note(note), NOTE_ON) if *note == number => *out = value as f32 / 127.0,
+            (MidiControl::Note(note), NOTE_OFF) if *note == number => *out = 0.0,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::net::UdpSocket;
+use rosc::{OscPacket, OscType};
+use crate::OSC_IN;
+
+// Largest packet we accept, plenty for a single float message
+const MAX_PACKET_SIZE: usize = 1536;
+
+// Binds the port and listens for float messages on a background thread. The last value sent
+// to `address` is stored in OSC_IN
+pub fn spawn_listener(port: u16, address: String) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    println!("Listening for OSC messages to {} on port {}", address, port);
+
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let size = match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => size,
+                Err(e) => {
+                    eprintln!("Warning: OSC listener stopped: {}", e);
+                    return;
+                }
+            };
+            match rosc::decoder::decode_udp(&buffer[..size]) {
+                Ok((_, packet)) => handle_packet(packet, &address),
+                Err(e) => eprintln!("Warning: Invalid OSC packet: {:?}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_packet(packet: OscPacket, address: &str) {
+    match packet {
+        OscPacket::Message(message) => {
+            if message.addr != address {
+                return;
+            }
+            // Control surfaces send ints and doubles as well, take whatever number comes first
+            let value = message.args.iter().find_map(|arg| match arg {
+                OscType::Float(value) => Some(*value),
+                OscType::Double(value) => Some(*value as f32),
+                OscType::Int(value) => Some(*value as f32),
+                _ => None,
+            });
+            if let Some(value) = value {
+                OSC_IN.store(value);
+            }
+        }
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(packet, address);
+            }
+        }
+    }
+}